@@ -0,0 +1,72 @@
+use crate::config::Config;
+use crate::{DnsPacket, DnsRecord, QueryClass, QueryType, ResultCode};
+
+/// What we answer to `version.bind` and `version.server`.
+const VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Fills in the response to a CHAOS class question. Only the names servers conventionally use
+/// to identify themselves exist there, anything else is refused.
+pub fn answer(qname: &str, qtype: QueryType, config: &Config, packet: &mut DnsPacket) {
+    let text = match qname {
+        "version.bind" | "version.server" => Some(VERSION),
+        "hostname.bind" | "id.server" => config.server_id.as_deref(),
+        _ => None,
+    };
+
+    let Some(text) = text else {
+        packet.header.rescode = ResultCode::REFUSED;
+        return;
+    };
+
+    packet.header.authoritative_answer = true;
+    // ANY questions get the TXT record like a TXT question would
+    if qtype == QueryType::TXT || qtype == QueryType::ANY {
+        packet.answers.push(DnsRecord::TXT {
+            domain: qname.to_string(),
+            strings: vec![text.as_bytes().to_vec()],
+            class: QueryClass::CH,
+            ttl: 0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ask(qname: &str, qtype: QueryType, config: &Config) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        answer(qname, qtype, config, &mut packet);
+        packet
+    }
+
+    #[test]
+    fn servers_identify_themselves() {
+        let config = Config {
+            server_id: Some("ns1".to_string()),
+            ..Default::default()
+        };
+        let text = |packet: &DnsPacket| match &packet.answers[..] {
+            [DnsRecord::TXT { strings, class, .. }] if *class == QueryClass::CH => {
+                String::from_utf8(strings.concat()).unwrap()
+            }
+            answers => panic!("Unexpected answers {:?}", answers),
+        };
+
+        for qtype in [QueryType::TXT, QueryType::ANY] {
+            assert_eq!(text(&ask("version.bind", qtype, &config)), VERSION);
+            assert_eq!(text(&ask("hostname.bind", qtype, &config)), "ns1");
+            assert_eq!(text(&ask("id.server", qtype, &config)), "ns1");
+        }
+
+        // The names exist for other types too, they just have nothing of them
+        let packet = ask("version.bind", QueryType::A, &config);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+
+        let packet = ask("id.server", QueryType::TXT, &Config::default());
+        assert_eq!(packet.header.rescode, ResultCode::REFUSED);
+        let packet = ask("example.com", QueryType::ANY, &config);
+        assert_eq!(packet.header.rescode, ResultCode::REFUSED);
+    }
+}
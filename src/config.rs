@@ -0,0 +1,728 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::zone;
+use crate::DnsRecord;
+
+/// The root servers as published by IANA, used when no other hints are configured.
+const BUILTIN_ROOT_HINTS: &str = include_str!("../root.hints");
+
+/// The DS records of the root key signing keys, used when no other trust anchors are configured.
+const BUILTIN_TRUST_ANCHORS: &str = include_str!("../root.anchors");
+
+const USAGE: &str = "Usage: dns-server [OPTIONS] [ZONE_FILE]...
+
+Options:
+  -c, --config <FILE>       Read settings from a TOML file, the options below override it
+  -l, --listen <ADDR>       Address and port to serve on, may be repeated
+      --cache-size <N>      Maximum number of RRsets to cache
+      --source <IP>         Source address for outbound queries, one per address family
+      --root-hint <IP>      Root server to start resolution from, may be repeated
+      --root-hints <FILE>   Master file listing the root servers, used when no --root-hint is given
+      --mode <MODE>         How names outside our zones are resolved: recursive or forwarding
+      --timeout <MS>        How long to wait for an upstream server before trying the next one
+      --retries <N>         How many more times to go through the servers when none of them answer
+      --forwarder <ADDR>    Upstream resolver used in forwarding mode, may be repeated
+      --forward-zone <ZONE>=<ADDR>[,<ADDR>...]
+                            Send names under ZONE to these resolvers instead, may be repeated
+      --server-id <ID>      Name of this instance, as reported to id.server CHAOS queries
+      --validate            Check DNSSEC signatures on resolved answers
+      --trust-anchors <FILE>
+                            Master file with the DS or DNSKEY records validation starts from
+      --zone-key <ZONE>=<FILE>[,<FILE>...]
+                            Sign ZONE with these .pem or BIND .private keys, may be repeated
+      --secondary <ZONE>=<ADDR>[,<ADDR>...]
+                            Serve a copy of ZONE transferred from these primaries, may be repeated
+      --allow-transfer <NET>
+                            Let clients in this network transfer our zones, may be repeated
+  -h, --help                Print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverMode {
+    /// Walk the delegation chain down from the root servers.
+    Recursive,
+    /// Hand every question to an upstream resolver.
+    Forwarding,
+}
+
+impl ResolverMode {
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "recursive" => Ok(Self::Recursive),
+            "forwarding" => Ok(Self::Forwarding),
+            _ => Err(anyhow!("Unknown mode {}", name)),
+        }
+    }
+}
+
+/// A block of addresses, such as `192.0.2.0/24`. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn parse(value: &str) -> Result<Self> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("Invalid network {}", value))?;
+
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in {}", value))?,
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (network, addr, bits) = match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                (u32::from(network) as u128, u32::from(addr) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network), u128::from(addr), 128),
+            _ => return false,
+        };
+
+        // Shifting by the full width would overflow
+        self.prefix_len == 0 || (network ^ addr) >> (bits - self.prefix_len as u32) == 0
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses the UDP and TCP listeners are bound to.
+    pub listen: Vec<SocketAddr>,
+    /// Maximum number of RRsets kept in the cache.
+    pub cache_size: usize,
+    /// Addresses outbound queries are sent from. The first one matching the family of the
+    /// server being queried is used, so there should be at most one per family. Servers of a
+    /// family without one are skipped, which by default are the IPv6 ones, as hosts without an
+    /// IPv6 route would wait out the timeout on every one of them.
+    pub source_addresses: Vec<IpAddr>,
+    /// Root servers to prime from. When empty they are read from `root_hints_file`, or from the
+    /// hints built into the server if there is no file either.
+    pub root_hints: Vec<IpAddr>,
+    pub root_hints_file: Option<PathBuf>,
+    /// Milliseconds to wait for an upstream server to answer.
+    pub query_timeout_ms: u64,
+    /// Extra passes over a set of servers when none of them gave a usable answer.
+    pub retries: usize,
+    pub mode: ResolverMode,
+    /// Upstream resolvers, tried in order until one of them answers.
+    #[serde(deserialize_with = "deserialize_servers")]
+    pub forwarders: Vec<SocketAddr>,
+    /// Zones whose names are always forwarded to their own resolvers, whatever the mode.
+    #[serde(deserialize_with = "deserialize_zone_servers")]
+    pub forward_zones: HashMap<String, Vec<SocketAddr>>,
+    /// Master files of the zones we are authoritative for.
+    pub zones: Vec<PathBuf>,
+    /// Identifies this instance in answers to `id.server` and `hostname.bind` CHAOS queries,
+    /// which are refused when it isn't set.
+    pub server_id: Option<String>,
+    /// Whether resolved answers are checked against their DNSSEC signatures.
+    pub dnssec_validation: bool,
+    /// DS or DNSKEY records to build chains of trust from, read from `trust_anchors_file` or
+    /// from the root anchors built into the server.
+    pub trust_anchors_file: Option<PathBuf>,
+    #[serde(skip)]
+    pub trust_anchors: Vec<DnsRecord>,
+    /// Private keys of the zones we sign as they are served.
+    #[serde(deserialize_with = "deserialize_zone_keys")]
+    pub zone_keys: HashMap<String, Vec<PathBuf>>,
+    /// Zones we are a secondary for, with the primaries we transfer them from.
+    #[serde(deserialize_with = "deserialize_zone_servers")]
+    pub secondary_zones: HashMap<String, Vec<SocketAddr>>,
+    /// Clients allowed to transfer our zones with AXFR and IXFR. Nobody is, by default.
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub allow_transfer: Vec<Network>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 2053)],
+            cache_size: 1000,
+            source_addresses: vec![Ipv4Addr::UNSPECIFIED.into()],
+            root_hints: Vec::new(),
+            root_hints_file: None,
+            query_timeout_ms: 2000,
+            retries: 2,
+            mode: ResolverMode::Recursive,
+            forwarders: Vec::new(),
+            forward_zones: HashMap::new(),
+            zones: Vec::new(),
+            server_id: None,
+            dnssec_validation: false,
+            trust_anchors_file: None,
+            trust_anchors: Vec::new(),
+            zone_keys: HashMap::new(),
+            secondary_zones: HashMap::new(),
+            allow_transfer: Vec::new(),
+        }
+    }
+}
+
+/// Parses a socket address, defaulting to the DNS port when none is given.
+fn parse_server(addr: &str) -> Result<SocketAddr> {
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+
+    addr.parse()
+        .map_err(|_| anyhow!("Invalid address {}", addr))
+}
+
+fn deserialize_servers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|addr| parse_server(addr).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn deserialize_zone_servers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Vec<SocketAddr>>, D::Error> {
+    HashMap::<String, Vec<String>>::deserialize(deserializer)?
+        .iter()
+        .map(|(zone, servers)| {
+            let servers = servers
+                .iter()
+                .map(|addr| parse_server(addr))
+                .collect::<Result<_>>()
+                .map_err(serde::de::Error::custom)?;
+            Ok((normalize_name(zone), servers))
+        })
+        .collect()
+}
+
+fn deserialize_zone_keys<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Vec<PathBuf>>, D::Error> {
+    Ok(HashMap::<String, Vec<PathBuf>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(zone, keys)| (normalize_name(&zone), keys))
+        .collect())
+}
+
+fn deserialize_networks<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Network>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|net| Network::parse(net).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Pulls the addresses of the root nameservers out of a hints file, which is a master file with
+/// the NS records of the root and the A and AAAA records of the servers they name.
+fn parse_root_hints(text: &str) -> Result<Vec<IpAddr>> {
+    let records = zone::parse_master_file(text, Some(""))?;
+
+    let hosts: Vec<&str> = records
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::NS { domain, host, .. } if domain.is_empty() => Some(host.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    Ok(records
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { domain, addr, .. } if hosts.contains(&domain.as_str()) => {
+                Some((*addr).into())
+            }
+            DnsRecord::AAAA { domain, addr, .. } if hosts.contains(&domain.as_str()) => {
+                Some((*addr).into())
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Reads trust anchors from a master file, keeping only the DS and DNSKEY records.
+fn parse_trust_anchors(text: &str) -> Result<Vec<DnsRecord>> {
+    Ok(zone::parse_master_file(text, Some(""))?
+        .into_iter()
+        .filter(|record| matches!(record, DnsRecord::DS { .. } | DnsRecord::DNSKEY { .. }))
+        .collect())
+}
+
+/// Parses a `--forward-zone` or `--secondary` value, a zone name and a comma separated list of
+/// servers.
+fn parse_zone_servers(value: &str) -> Result<(String, Vec<SocketAddr>)> {
+    let (zone, servers) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid zone {}, expected ZONE=ADDR", value))?;
+    let servers = servers
+        .split(',')
+        .map(parse_server)
+        .collect::<Result<_>>()?;
+
+    Ok((normalize_name(zone), servers))
+}
+
+/// Parses a `--zone-key` value, a zone name and a comma separated list of key files.
+fn parse_zone_key(value: &str) -> Result<(String, Vec<PathBuf>)> {
+    let (zone, keys) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid zone key {}, expected ZONE=FILE", value))?;
+
+    Ok((
+        normalize_name(zone),
+        keys.split(',').map(PathBuf::from).collect(),
+    ))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+
+        toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    /// Builds the configuration from the command line, on top of the config file it points to
+    /// if there is one. Options given on the command line win over the file.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut config_path = None;
+        let mut cache_size = None;
+        let mut query_timeout_ms = None;
+        let mut retries = None;
+        let mut root_hints_file = None;
+        let mut mode = None;
+        let mut server_id = None;
+        let mut dnssec_validation = false;
+        let mut trust_anchors_file = None;
+
+        // Options that may be repeated replace what the file says the first time they are seen
+        let mut listen = Vec::new();
+        let mut source_addresses = Vec::new();
+        let mut root_hints = Vec::new();
+        let mut forwarders = Vec::new();
+        let mut forward_zones = HashMap::new();
+        let mut zones = Vec::new();
+        let mut zone_keys = HashMap::new();
+        let mut allow_transfer = Vec::new();
+        let mut secondary_zones = HashMap::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "-c" | "--config" => config_path = Some(PathBuf::from(value()?)),
+                "-l" | "--listen" => listen.push(
+                    value()?
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid listen address"))?,
+                ),
+                "--cache-size" => {
+                    cache_size = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| anyhow!("Invalid cache size"))?,
+                    )
+                }
+                "--source" => source_addresses.push(
+                    value()?
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid source address"))?,
+                ),
+                "--root-hint" => {
+                    root_hints.push(value()?.parse().map_err(|_| anyhow!("Invalid root hint"))?)
+                }
+                "--timeout" => {
+                    query_timeout_ms =
+                        Some(value()?.parse().map_err(|_| anyhow!("Invalid timeout"))?)
+                }
+                "--retries" => {
+                    retries = Some(
+                        value()?
+                            .parse()
+                            .map_err(|_| anyhow!("Invalid retry count"))?,
+                    )
+                }
+                "--root-hints" => root_hints_file = Some(PathBuf::from(value()?)),
+                "--mode" => mode = Some(ResolverMode::from_name(&value()?)?),
+                "--forwarder" => forwarders.push(parse_server(&value()?)?),
+                "--forward-zone" => {
+                    let (zone, servers) = parse_zone_servers(&value()?)?;
+                    forward_zones.insert(zone, servers);
+                }
+                "--server-id" => server_id = Some(value()?),
+                "--validate" => dnssec_validation = true,
+                "--trust-anchors" => trust_anchors_file = Some(PathBuf::from(value()?)),
+                "--secondary" => {
+                    let (zone, primaries) = parse_zone_servers(&value()?)?;
+                    secondary_zones.insert(zone, primaries);
+                }
+                "--allow-transfer" => allow_transfer.push(Network::parse(&value()?)?),
+                "--zone-key" => {
+                    let (zone, keys) = parse_zone_key(&value()?)?;
+                    zone_keys.insert(zone, keys);
+                }
+                flag if flag.starts_with('-') => {
+                    return Err(anyhow!("Unknown option {}\n\n{}", flag, USAGE))
+                }
+                _ => zones.push(PathBuf::from(arg)),
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Self::load(&path)?,
+            None => Self::default(),
+        };
+
+        if let Some(cache_size) = cache_size {
+            config.cache_size = cache_size;
+        }
+        if let Some(query_timeout_ms) = query_timeout_ms {
+            config.query_timeout_ms = query_timeout_ms;
+        }
+        if let Some(retries) = retries {
+            config.retries = retries;
+        }
+        if root_hints_file.is_some() {
+            config.root_hints_file = root_hints_file;
+        }
+        if let Some(mode) = mode {
+            config.mode = mode;
+        }
+        if server_id.is_some() {
+            config.server_id = server_id;
+        }
+        if dnssec_validation {
+            config.dnssec_validation = true;
+        }
+        if trust_anchors_file.is_some() {
+            config.trust_anchors_file = trust_anchors_file;
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        if !source_addresses.is_empty() {
+            config.source_addresses = source_addresses;
+        }
+        if !root_hints.is_empty() {
+            config.root_hints = root_hints;
+        }
+        if !forwarders.is_empty() {
+            config.forwarders = forwarders;
+        }
+        if !forward_zones.is_empty() {
+            config.forward_zones = forward_zones;
+        }
+        if !zone_keys.is_empty() {
+            config.zone_keys = zone_keys;
+        }
+        if !allow_transfer.is_empty() {
+            config.allow_transfer = allow_transfer;
+        }
+        if !secondary_zones.is_empty() {
+            config.secondary_zones = secondary_zones;
+        }
+        config.zones.extend(zones);
+
+        if config.root_hints.is_empty() {
+            config.root_hints = match &config.root_hints_file {
+                Some(path) => {
+                    let text = fs::read_to_string(path)?;
+                    parse_root_hints(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+                }
+                None => parse_root_hints(BUILTIN_ROOT_HINTS)?,
+            };
+        }
+
+        config.trust_anchors = match &config.trust_anchors_file {
+            Some(path) => {
+                let text = fs::read_to_string(path)?;
+                parse_trust_anchors(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            }
+            None => parse_trust_anchors(BUILTIN_TRUST_ANCHORS)?,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            return Err(anyhow!("No listen addresses configured"));
+        }
+        if self.query_timeout_ms == 0 {
+            return Err(anyhow!("The query timeout can't be zero"));
+        }
+        if self.mode == ResolverMode::Recursive && self.root_hints.is_empty() {
+            return Err(anyhow!("Recursive mode needs at least one root hint"));
+        }
+        if self.mode == ResolverMode::Forwarding && self.forwarders.is_empty() {
+            return Err(anyhow!("Forwarding mode needs at least one forwarder"));
+        }
+        if self.dnssec_validation && self.trust_anchors.is_empty() {
+            return Err(anyhow!("Validation needs at least one trust anchor"));
+        }
+        if let Some((zone, _)) = self
+            .forward_zones
+            .iter()
+            .find(|(_, servers)| servers.is_empty())
+        {
+            return Err(anyhow!("No forwarders configured for zone {}", zone));
+        }
+        if let Some((zone, _)) = self
+            .secondary_zones
+            .iter()
+            .find(|(_, primaries)| primaries.is_empty())
+        {
+            return Err(anyhow!(
+                "No primaries configured for secondary zone {}",
+                zone
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The resolvers a question about `qname` should be forwarded to, or `None` if it should be
+    /// resolved recursively. The forward zone closest to the name wins.
+    pub fn forwarders_for(&self, qname: &str) -> Option<&[SocketAddr]> {
+        let mut current = Some(qname);
+        while let Some(name) = current {
+            if let Some(servers) = self.forward_zones.get(name) {
+                return Some(servers);
+            }
+            current = zone::parent(name);
+        }
+
+        match self.mode {
+            ResolverMode::Forwarding => Some(&self.forwarders),
+            ResolverMode::Recursive => None,
+        }
+    }
+
+    /// The trust anchor closest above `name`, which its chain of trust starts from, if any.
+    pub fn closest_trust_anchor<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let mut current = Some(name);
+        while let Some(candidate) = current {
+            if self
+                .trust_anchors
+                .iter()
+                .any(|anchor| anchor.domain() == candidate)
+            {
+                return Some(candidate);
+            }
+            current = zone::parent(candidate);
+        }
+
+        None
+    }
+
+    /// The trust anchors configured for exactly `zone`.
+    pub fn trust_anchors_for(&self, zone: &str) -> Vec<DnsRecord> {
+        self.trust_anchors
+            .iter()
+            .filter(|anchor| anchor.domain() == zone)
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `client` may transfer our zones.
+    pub fn may_transfer(&self, client: IpAddr) -> bool {
+        self.allow_transfer.iter().any(|net| net.contains(client))
+    }
+
+    pub fn query_timeout(&self) -> Duration {
+        Duration::from_millis(self.query_timeout_ms)
+    }
+
+    /// Whether there is a source address to reach `server` from.
+    pub fn can_reach(&self, server: &SocketAddr) -> bool {
+        self.source_for(server).is_ok()
+    }
+
+    /// The address to send queries to `server` from.
+    pub fn source_for(&self, server: &SocketAddr) -> Result<IpAddr> {
+        self.source_addresses
+            .iter()
+            .find(|addr| addr.is_ipv4() == server.is_ipv4())
+            .copied()
+            .ok_or_else(|| anyhow!("No source address configured to reach {}", server))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> Config {
+        Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn command_line_options_win_over_the_file() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "cache_size = 500
+            retries = 5
+            mode = \"forwarding\"
+            forwarders = [\"192.0.2.53\"]
+            server_id = \"from-file\"
+",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = from_args(&["-c", path]);
+        assert_eq!(config.cache_size, 500);
+        assert_eq!(config.retries, 5);
+        assert_eq!(config.mode, ResolverMode::Forwarding);
+        assert_eq!(config.server_id.as_deref(), Some("from-file"));
+
+        // The value of another option is never taken for the config file
+        let config = from_args(&[
+            "--server-id",
+            "-c",
+            "--config",
+            path,
+            "--retries",
+            "1",
+            "--forwarder",
+            "192.0.2.54:5353",
+        ]);
+        assert_eq!(config.cache_size, 500);
+        assert_eq!(config.retries, 1);
+        assert_eq!(config.server_id.as_deref(), Some("-c"));
+        assert_eq!(config.forwarders, ["192.0.2.54:5353".parse().unwrap()]);
+
+        fs::remove_file(path).unwrap();
+        assert!(Config::from_args(["-c".to_string()]).is_err());
+        assert_eq!(from_args(&[]).cache_size, Config::default().cache_size);
+    }
+
+    #[test]
+    fn forward_zones_closest_to_the_name_win() {
+        let config = from_args(&[
+            "--forward-zone",
+            "Example.com.=192.0.2.1",
+            "--forward-zone",
+            "sub.example.com=192.0.2.2",
+        ]);
+        let forwarder = |qname: &str| {
+            config
+                .forwarders_for(qname)
+                .map(|servers| servers[0].to_string())
+        };
+
+        assert_eq!(
+            forwarder("www.example.com").as_deref(),
+            Some("192.0.2.1:53")
+        );
+        assert_eq!(forwarder("example.com").as_deref(), Some("192.0.2.1:53"));
+        assert_eq!(
+            forwarder("a.sub.example.com").as_deref(),
+            Some("192.0.2.2:53")
+        );
+        assert_eq!(
+            forwarder("sub.example.com").as_deref(),
+            Some("192.0.2.2:53")
+        );
+        assert_eq!(
+            forwarder("notsub.example.com").as_deref(),
+            Some("192.0.2.1:53")
+        );
+        assert_eq!(forwarder("example.org"), None);
+
+        // In forwarding mode, everything else goes to the forwarders
+        let config = from_args(&[
+            "--mode",
+            "forwarding",
+            "--forwarder",
+            "192.0.2.53",
+            "--forward-zone",
+            "example.com=192.0.2.1",
+        ]);
+        assert_eq!(
+            config.forwarders_for("example.org"),
+            Some(&["192.0.2.53:53".parse().unwrap()][..])
+        );
+        assert_eq!(
+            config.forwarders_for("www.example.com"),
+            Some(&["192.0.2.1:53".parse().unwrap()][..])
+        );
+    }
+
+    #[test]
+    fn servers_are_reached_from_a_source_of_their_family() {
+        let v4: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+
+        let config = Config::default();
+        assert_eq!(config.source_for(&v4).unwrap(), Ipv4Addr::UNSPECIFIED);
+        assert!(config.can_reach(&v4));
+        assert!(config.source_for(&v6).is_err());
+        assert!(!config.can_reach(&v6));
+
+        let config = from_args(&["--source", "192.0.2.10", "--source", "2001:db8::10"]);
+        assert_eq!(
+            config.source_for(&v4).unwrap(),
+            "192.0.2.10".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            config.source_for(&v6).unwrap(),
+            "2001:db8::10".parse::<IpAddr>().unwrap()
+        );
+        assert!(config.can_reach(&v6));
+    }
+
+    #[test]
+    fn transfers_are_allowed_by_network() {
+        let config = from_args(&[
+            "--allow-transfer",
+            "192.0.2.0/24",
+            "--allow-transfer",
+            "2001:db8::53",
+        ]);
+
+        let allowed = |addr: &str| config.may_transfer(addr.parse().unwrap());
+        assert!(allowed("192.0.2.1"));
+        assert!(allowed("192.0.2.255"));
+        assert!(!allowed("192.0.3.1"));
+        assert!(allowed("2001:db8::53"));
+        assert!(!allowed("2001:db8::54"));
+
+        assert!(Network::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("203.0.113.9".parse().unwrap()));
+        assert!(Network::parse("192.0.2.0/33").is_err());
+        assert!(!Config::default().may_transfer("127.0.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,459 @@
+use anyhow::{anyhow, Result};
+use data_encoding::BASE32HEX_NOPAD;
+use ring::{digest, signature};
+use std::cmp::Ordering;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{BytePacketWriter, DnsRecord, QueryType};
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// Encodes the set of types present at a name as the windowed bitmap of NSEC and NSEC3
+/// records (RFC 4034 section 4.1.2).
+pub fn encode_type_bitmap(types: &[QueryType]) -> Vec<u8> {
+    let mut numbers: Vec<u16> = types.iter().map(QueryType::to_num).collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+
+    let mut data = Vec::new();
+    let mut numbers = numbers.into_iter().peekable();
+    while let Some(&first) = numbers.peek() {
+        let window = (first >> 8) as u8;
+
+        let mut bitmap = [0u8; 32];
+        let mut len = 0;
+        while let Some(num) = numbers.next_if(|num| (num >> 8) as u8 == window) {
+            let low = (num & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+        }
+
+        data.push(window);
+        data.push(len as u8);
+        data.extend_from_slice(&bitmap[..len]);
+    }
+
+    data
+}
+
+pub fn decode_type_bitmap(mut data: &[u8]) -> Result<Vec<QueryType>> {
+    let mut types = Vec::new();
+
+    while !data.is_empty() {
+        let (window, len) = match data {
+            [window, len, ..] if (1..=32).contains(len) => (*window as u16, *len as usize),
+            _ => return Err(anyhow!("Malformed type bitmap")),
+        };
+        let bitmap = data
+            .get(2..2 + len)
+            .ok_or_else(|| anyhow!("Type bitmap overruns the record data"))?;
+
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(QueryType::from_num(window << 8 | (i * 8 + bit) as u16));
+                }
+            }
+        }
+
+        data = &data[2 + len..];
+    }
+
+    Ok(types)
+}
+
+/// DNSKEY flag of keys that sign zone data, the only ones signatures are checked against.
+pub const DNSKEY_ZONE_KEY: u16 = 0x0100;
+
+/// NSEC3 flag saying unsigned delegations may have been left out of the chain (RFC 5155).
+pub const NSEC3_OPT_OUT: u8 = 0x01;
+
+/// The only protocol a DNSKEY may have (RFC 4034 section 2.1.2).
+const DNSKEY_PROTOCOL: u8 = 3;
+
+/// Whether signatures made with `algorithm` can be checked (RFC 8624).
+pub fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+fn digest_algorithm(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    digest_algorithm(digest_type).is_some()
+}
+
+/// A name in the uncompressed wire format used by digests and hashes.
+pub fn wire_name(name: &str) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketWriter::without_compression();
+    buffer.write_qname(name)?;
+
+    Ok(buffer.buf)
+}
+
+fn dnskey_rdata(key: &DnsRecord) -> Option<Vec<u8>> {
+    match key {
+        DnsRecord::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            public_key,
+            ..
+        } => {
+            let mut rdata = flags.to_be_bytes().to_vec();
+            rdata.push(*protocol);
+            rdata.push(*algorithm);
+            rdata.extend_from_slice(public_key);
+            Some(rdata)
+        }
+        _ => None,
+    }
+}
+
+/// The tag RRSIG and DS records use to point at a DNSKEY (RFC 4034 appendix B).
+pub fn key_tag(key: &DnsRecord) -> Option<u16> {
+    let rdata = dnskey_rdata(key)?;
+
+    let mut sum: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        sum += if i % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    sum += (sum >> 16) & 0xFFFF;
+
+    Some((sum & 0xFFFF) as u16)
+}
+
+/// Whether `ds` is the digest of `key`, a DNSKEY of the zone the DS record is for.
+pub fn ds_matches(ds: &DnsRecord, key: &DnsRecord) -> bool {
+    let DnsRecord::DS {
+        domain,
+        key_tag: tag,
+        algorithm,
+        digest_type,
+        digest,
+        ..
+    } = ds
+    else {
+        return false;
+    };
+    let DnsRecord::DNSKEY {
+        domain: key_domain,
+        algorithm: key_algorithm,
+        ..
+    } = key
+    else {
+        return false;
+    };
+    if domain != key_domain || algorithm != key_algorithm || key_tag(key) != Some(*tag) {
+        return false;
+    }
+
+    let (Some(algorithm), Ok(mut data), Some(rdata)) = (
+        digest_algorithm(*digest_type),
+        wire_name(domain),
+        dnskey_rdata(key),
+    ) else {
+        return false;
+    };
+    data.extend_from_slice(&rdata);
+
+    digest::digest(algorithm, &data).as_ref() == digest.as_slice()
+}
+
+/// Checks a signature made with a DNSKEY of the given algorithm over `message`.
+pub fn verify_signature(
+    algorithm: u8,
+    public_key: &[u8],
+    message: &[u8],
+    signature_data: &[u8],
+) -> Result<()> {
+    let result = match algorithm {
+        5 | 7 | 8 | 10 => {
+            let parameters = match algorithm {
+                5 | 7 => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+                8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                _ => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+            };
+
+            // The exponent length takes one byte, or three when it doesn't fit (RFC 3110)
+            let (exponent_len, rest) = match public_key {
+                [0, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+                [len, rest @ ..] => (*len as usize, rest),
+                [] => return Err(anyhow!("Empty RSA public key")),
+            };
+            if rest.len() <= exponent_len {
+                return Err(anyhow!("Malformed RSA public key"));
+            }
+            let (e, n) = rest.split_at(exponent_len);
+
+            signature::RsaPublicKeyComponents { n, e }.verify(parameters, message, signature_data)
+        }
+        13 | 14 => {
+            let parameters = match algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+
+            // DNSKEYs hold the bare coordinates of the point, without the uncompressed marker
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            signature::UnparsedPublicKey::new(parameters, point).verify(message, signature_data)
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, signature_data),
+        _ => return Err(anyhow!("Unsupported algorithm {}", algorithm)),
+    };
+
+    result.map_err(|_| anyhow!("Signature does not verify"))
+}
+
+/// Whether `key` may have made `rrsig`: a zone key of the signer with the same tag and algorithm.
+pub fn key_signed(key: &DnsRecord, rrsig: &DnsRecord) -> bool {
+    match (key, rrsig) {
+        (
+            DnsRecord::DNSKEY {
+                domain,
+                flags,
+                protocol,
+                algorithm,
+                ..
+            },
+            DnsRecord::RRSIG {
+                algorithm: sig_algorithm,
+                key_tag: tag,
+                signer_name,
+                ..
+            },
+        ) => {
+            domain == signer_name
+                && flags & DNSKEY_ZONE_KEY != 0
+                && *protocol == DNSKEY_PROTOCOL
+                && algorithm == sig_algorithm
+                && key_tag(key) == Some(*tag)
+        }
+        _ => false,
+    }
+}
+
+/// Number of labels in a name, not counting the root or a leading wildcard, as RRSIGs count them.
+pub fn label_count(name: &str) -> u8 {
+    let name = name
+        .strip_prefix('*')
+        .unwrap_or(name)
+        .trim_start_matches('.');
+    match name {
+        "" => 0,
+        name => name.split('.').count() as u8,
+    }
+}
+
+/// What an RRSIG signs: its own RDATA up to the signature, then the RRset in canonical form
+/// (RFC 4034 section 3.1.8.1). Records expanded from a wildcard are signed under the wildcard.
+pub fn signed_data(rrsig: &DnsRecord, rrset: &[DnsRecord]) -> Result<Vec<u8>> {
+    let DnsRecord::RRSIG {
+        type_covered,
+        algorithm,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag,
+        signer_name,
+        ..
+    } = rrsig
+    else {
+        return Err(anyhow!("Not an RRSIG"));
+    };
+
+    let mut buffer = BytePacketWriter::without_compression();
+    buffer.write_u16(type_covered.to_num())?;
+    buffer.write_u8(*algorithm)?;
+    buffer.write_u8(*labels)?;
+    buffer.write_u32(*original_ttl)?;
+    buffer.write_u32(*expiration)?;
+    buffer.write_u32(*inception)?;
+    buffer.write_u16(*key_tag)?;
+    buffer.write_qname(signer_name)?;
+    let mut data = buffer.buf;
+
+    let mut rrset = rrset.to_vec();
+    for record in &mut rrset {
+        let owner = record.domain();
+        if label_count(&owner) > *labels {
+            let kept: Vec<&str> = owner.rsplit('.').take(*labels as usize).collect();
+            let wildcard: Vec<&str> = std::iter::once("*").chain(kept.into_iter().rev()).collect();
+            record.set_domain(wildcard.join("."));
+        }
+    }
+    data.extend(DnsRecord::canonical_rrset(&rrset, *original_ttl)?);
+
+    Ok(data)
+}
+
+/// Whether `now` lies between the inception and expiration of a signature, in the serial number
+/// arithmetic of RFC 1982 so that the times can wrap around.
+pub fn in_validity_period(inception: u32, expiration: u32, now: u32) -> bool {
+    (now.wrapping_sub(inception) as i32) >= 0 && (expiration.wrapping_sub(now) as i32) >= 0
+}
+
+/// The hashed owner name NSEC3 uses for `name` (RFC 5155 section 5).
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Result<Vec<u8>> {
+    let mut hash = wire_name(name)?;
+    for _ in 0..=iterations {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(&hash);
+        context.update(salt);
+        hash = context.finish().as_ref().to_vec();
+    }
+
+    Ok(hash)
+}
+
+/// Whether an NSEC from `owner` to `next` proves there is nothing at `name`, which has to fall
+/// strictly between the two. The last NSEC of a zone points back to the apex.
+pub fn nsec_covers(owner: &str, next: &str, name: &str) -> bool {
+    canonical_cmp(owner, name) == Ordering::Less
+        && (canonical_cmp(name, next) == Ordering::Less
+            || canonical_cmp(next, owner) != Ordering::Greater)
+}
+
+/// Whether an NSEC3 hashed from `owner` to `next` covers `hash`, wrapping around at the end of
+/// the chain like NSEC does.
+pub fn nsec3_covers(owner: &[u8], next: &[u8], hash: &[u8]) -> bool {
+    owner < hash && (hash < next || next <= owner)
+}
+
+/// The hash an NSEC3 record is for, which its first label holds in base32.
+pub fn nsec3_owner_hash(owner: &str) -> Option<Vec<u8>> {
+    let label = owner.split('.').next()?;
+
+    BASE32HEX_NOPAD
+        .decode(label.to_ascii_uppercase().as_bytes())
+        .ok()
+}
+
+/// The owner name of the NSEC3 record for `hash` in `zone`.
+pub fn nsec3_owner(hash: &[u8], zone: &str) -> String {
+    let label = BASE32HEX_NOPAD.encode(hash).to_ascii_lowercase();
+
+    match zone {
+        "" => label,
+        zone => format!("{}.{}", label, zone),
+    }
+}
+
+/// The current time, as RRSIG inception and expiration times count it.
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+/// Orders names the way NSEC chains do (RFC 4034 section 6.1), label by label from the root.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name| match name {
+        "" => None.into_iter().flatten(),
+        name => Some(name.rsplit('.')).into_iter().flatten(),
+    };
+
+    // Case doesn't count, and nothing is allocated, as zones look names up in this order
+    let (mut a, mut b) = (labels(a), labels(b));
+    loop {
+        match (a.next(), b.next()) {
+            (Some(a), Some(b)) => match a
+                .bytes()
+                .map(|byte| byte.to_ascii_lowercase())
+                .cmp(b.bytes().map(|byte| byte.to_ascii_lowercase()))
+            {
+                Ordering::Equal => continue,
+                order => return order,
+            },
+            (a, b) => return a.is_some().cmp(&b.is_some()),
+        }
+    }
+}
+
+/// An RRSIG inception or expiration time, shown as YYYYMMDDHHmmSS in UTC.
+pub struct Timestamp(pub u32);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0 as i64;
+        let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+        let time = secs.rem_euclid(SECS_PER_DAY);
+
+        write!(
+            f,
+            "{:04}{:02}{:02}{:02}{:02}{:02}",
+            year,
+            month,
+            day,
+            time / 3600,
+            time / 60 % 60,
+            time % 60
+        )
+    }
+}
+
+/// Parses an RRSIG time, either as YYYYMMDDHHmmSS or as plain seconds since the epoch.
+pub fn parse_timestamp(token: &str) -> Option<u32> {
+    if token.len() != 14 {
+        return token.parse().ok();
+    }
+    if !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| token[range].parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let secs =
+        days_from_civil(year, month, day) * SECS_PER_DAY + hour * 3600 + minute * 60 + second;
+    u32::try_from(secs).ok()
+}
+
+/// Turns days since 1970-01-01 into a proleptic Gregorian date.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months counted from March, so the leap day comes last
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = (month + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
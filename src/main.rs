@@ -1,24 +1,230 @@
 use anyhow::{anyhow, Result};
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use data_encoding::{BASE32HEX_NOPAD, BASE64, HEXUPPER};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::Duration;
-use ttl_cache::{Entry, TtlCache};
+use std::time::{Duration, Instant};
+use ttl_cache::TtlCache;
 
-const BUF_LEN: usize = 2048; // TODO: Implement EDNS(0)
+use config::{Config, ResolverMode};
+use resolution::{LimitExceeded, ResolutionContext};
+use rtt::RttTable;
+use secondary::Secondaries;
+use validation::{Bogus, Security};
+use zone::{is_subdomain, PendingZone, ZoneStore};
 
-type DnsCache = TtlCache<String, DnsRecord>;
-type SharedDnsCache = Arc<RwLock<DnsCache>>;
+mod chaos;
+mod config;
+mod dnssec;
+mod resolution;
+mod rtt;
+mod secondary;
+mod signing;
+mod transfer;
+mod validation;
+mod zone;
+
+const BUF_LEN: usize = 4096;
+
+/// Largest message that fits behind the two byte length prefix used over TCP.
+const MAX_PACKET_LEN: usize = 65535;
+
+/// UDP payload size we advertise in our own OPT records.
+const EDNS_UDP_PAYLOAD_SIZE: u16 = BUF_LEN as u16;
+
+/// Largest UDP message a client without EDNS(0) is able to receive.
+const DEFAULT_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// Opcode of standard queries.
+const OPCODE_QUERY: u8 = 0;
+
+/// Opcode of the messages primaries send when a zone changes (RFC 1996).
+const OPCODE_NOTIFY: u8 = 4;
+
+/// Option code of Extended DNS Errors (RFC 8914).
+const EDNS_OPTION_EDE: u16 = 15;
+
+/// How long a TCP connection may sit idle between queries before we close it.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most records put in one message of a zone transfer, fewer if they don't fit.
+const MAX_TRANSFER_RECORDS: usize = 1000;
+
+/// How long to wait before priming the root servers again after a failed attempt.
+const PRIME_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often zone files are checked for changes.
+const ZONE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the zones we sign get fresh signatures, well within how long they stay valid.
+const RESIGN_INTERVAL: Duration = Duration::from_secs(86400);
+
+/// Identifies an RRset: all the records sharing an owner name, type and class.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RRsetKey {
+    pub name: String,
+    pub qtype: QueryType,
+    pub class: QueryClass,
+}
+
+impl RRsetKey {
+    pub fn new(name: &str, qtype: QueryType, class: QueryClass) -> Self {
+        Self {
+            name: name.to_string(),
+            qtype,
+            class,
+        }
+    }
+}
+
+/// A cached NXDOMAIN or NODATA answer, along with the SOA it came with and the NSEC, NSEC3 and
+/// RRSIG records proving it.
+#[derive(Clone, Debug)]
+pub struct NegativeAnswer {
+    rescode: ResultCode,
+    soa: DnsRecord,
+    proof: Vec<DnsRecord>,
+}
+
+#[derive(Clone, Debug)]
+pub enum CacheEntry {
+    /// An RRset with its signatures. Records expanded from a wildcard keep the NSEC or NSEC3
+    /// records proving that the name they were expanded for doesn't exist.
+    Records {
+        records: Vec<DnsRecord>,
+        proof: Vec<DnsRecord>,
+    },
+    Negative(NegativeAnswer),
+}
+
+struct CachedEntry {
+    entry: CacheEntry,
+    expires: Instant,
+}
+
+pub struct DnsCache {
+    entries: TtlCache<RRsetKey, CachedEntry>,
+}
+
+impl DnsCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: TtlCache::new(capacity),
+        }
+    }
+
+    /// Looks up an RRset, with its TTLs lowered to the time it has left in the cache.
+    fn get(&mut self, name: &str, qtype: QueryType, class: QueryClass) -> Option<CacheEntry> {
+        let key = RRsetKey::new(name, qtype, class);
+        let expires = self.entries.get(&key)?.expires;
+
+        let remaining = expires.saturating_duration_since(Instant::now()).as_secs() as u32;
+        if remaining == 0 {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        let mut entry = self.entries.get(&key)?.entry.clone();
+        match &mut entry {
+            CacheEntry::Records { records, proof } => {
+                records
+                    .iter_mut()
+                    .chain(proof)
+                    .for_each(|rec| rec.set_ttl(remaining));
+            }
+            CacheEntry::Negative(negative) => {
+                negative.soa.set_ttl(remaining);
+                negative
+                    .proof
+                    .iter_mut()
+                    .for_each(|rec| rec.set_ttl(remaining));
+            }
+        }
+
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: RRsetKey, entry: CacheEntry, ttl: u32) {
+        let ttl = Duration::from_secs(ttl as u64);
+        let cached = CachedEntry {
+            entry,
+            expires: Instant::now() + ttl,
+        };
+
+        self.entries.insert(key, cached, ttl);
+    }
+
+    /// Caches every RRset found in `records`, each replacing whatever was cached for it. A set
+    /// expires with the lowest TTL among its records. Signatures are kept with the RRset they
+    /// cover, and `proof` with the RRsets expanded from a wildcard.
+    fn insert_records(&mut self, records: &[DnsRecord], proof: &[DnsRecord]) {
+        let mut rrsets: HashMap<RRsetKey, Vec<DnsRecord>> = HashMap::new();
+        for rec in records {
+            let qtype = match rec {
+                DnsRecord::RRSIG { type_covered, .. } => *type_covered,
+                rec => rec.qtype(),
+            };
+            rrsets
+                .entry(RRsetKey::new(&rec.domain(), qtype, rec.class()))
+                .or_default()
+                .push(rec.clone());
+        }
+
+        for (key, rrset) in rrsets {
+            let expanded = rrset.iter().any(|rec| match rec {
+                DnsRecord::RRSIG { domain, labels, .. } => *labels < dnssec::label_count(domain),
+                _ => false,
+            });
+            let proof = if expanded { proof.to_vec() } else { Vec::new() };
+
+            let ttl = rrset.iter().chain(&proof).map(DnsRecord::ttl).min();
+            let entry = CacheEntry::Records {
+                records: rrset,
+                proof,
+            };
+            self.insert(key, entry, ttl.unwrap_or(0));
+        }
+    }
+
+    fn insert_negative(&mut self, name: &str, qtype: QueryType, negative: NegativeAnswer) {
+        // Negative answers may be kept for as long as the SOA minimum allows (RFC 2308)
+        let ttl = match negative.soa {
+            DnsRecord::SOA { minimum, ttl, .. } => minimum.min(ttl),
+            _ => 0,
+        };
+
+        self.insert(
+            RRsetKey::new(name, qtype, negative.soa.class()),
+            CacheEntry::Negative(negative),
+            ttl,
+        );
+    }
+}
+
+/// Everything the listener threads share.
+pub struct ServerContext {
+    pub config: Config,
+    pub cache: RwLock<DnsCache>,
+    pub rtt: RwLock<RttTable>,
+    pub zones: RwLock<ZoneStore>,
+    pub secondaries: Secondaries,
+    /// Addresses of the root nameservers, the configured hints until priming replaces them.
+    pub root_servers: RwLock<Vec<IpAddr>>,
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResultCode {
-    NOERROR,
-    FORMERR,
-    SERVFAIL,
-    NXDOMAIN,
-    NOTIMP,
-    REFUSED,
+    NOERROR = 0,
+    FORMERR = 1,
+    SERVFAIL = 2,
+    NXDOMAIN = 3,
+    NOTIMP = 4,
+    REFUSED = 5,
+    NOTAUTH = 9,
 }
 
 impl ResultCode {
@@ -29,19 +235,38 @@ impl ResultCode {
             3 => Self::NXDOMAIN,
             4 => Self::NOTIMP,
             5 => Self::REFUSED,
-            0 | _ => Self::NOERROR,
+            9 => Self::NOTAUTH,
+            _ => Self::NOERROR,
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
+    TXT,
     AAAA,
+    SRV,
+    OPT,
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    NSEC3PARAM,
+    CAA,
+    /// Incremental zone transfer, only found in questions (RFC 1995).
+    IXFR,
+    /// Full zone transfer, only found in questions (RFC 5936).
+    AXFR,
+    /// Every record at a name, only found in questions.
+    ANY,
 }
 
 impl QueryType {
@@ -51,8 +276,23 @@ impl QueryType {
             Self::A => 1,
             Self::NS => 2,
             Self::CNAME => 5,
+            Self::SOA => 6,
+            Self::PTR => 12,
             Self::MX => 15,
+            Self::TXT => 16,
             Self::AAAA => 28,
+            Self::SRV => 33,
+            Self::OPT => 41,
+            Self::DS => 43,
+            Self::RRSIG => 46,
+            Self::NSEC => 47,
+            Self::DNSKEY => 48,
+            Self::NSEC3 => 50,
+            Self::NSEC3PARAM => 51,
+            Self::CAA => 257,
+            Self::IXFR => 251,
+            Self::AXFR => 252,
+            Self::ANY => 255,
         }
     }
 
@@ -61,48 +301,197 @@ impl QueryType {
             1 => Self::A,
             2 => Self::NS,
             5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
             15 => Self::MX,
+            16 => Self::TXT,
             28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
+            43 => Self::DS,
+            46 => Self::RRSIG,
+            47 => Self::NSEC,
+            48 => Self::DNSKEY,
+            50 => Self::NSEC3,
+            51 => Self::NSEC3PARAM,
+            257 => Self::CAA,
+            251 => Self::IXFR,
+            252 => Self::AXFR,
+            255 => Self::ANY,
+            _ => Self::UNKNOWN(num),
+        }
+    }
+
+    /// Parses a type mnemonic as used in master files, including the generic `TYPE123` form.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "A" => Some(Self::A),
+            "NS" => Some(Self::NS),
+            "CNAME" => Some(Self::CNAME),
+            "SOA" => Some(Self::SOA),
+            "PTR" => Some(Self::PTR),
+            "MX" => Some(Self::MX),
+            "TXT" => Some(Self::TXT),
+            "AAAA" => Some(Self::AAAA),
+            "SRV" => Some(Self::SRV),
+            "DS" => Some(Self::DS),
+            "RRSIG" => Some(Self::RRSIG),
+            "NSEC" => Some(Self::NSEC),
+            "DNSKEY" => Some(Self::DNSKEY),
+            "NSEC3" => Some(Self::NSEC3),
+            "NSEC3PARAM" => Some(Self::NSEC3PARAM),
+            "CAA" => Some(Self::CAA),
+            "IXFR" => Some(Self::IXFR),
+            "AXFR" => Some(Self::AXFR),
+            "ANY" => Some(Self::ANY),
+            name => name
+                .strip_prefix("TYPE")
+                .and_then(|num| num.parse().ok())
+                .map(Self::from_num),
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
+pub enum QueryClass {
+    UNKNOWN(u16),
+    IN,
+    CS,
+    CH,
+    HS,
+    /// Only seen in dynamic updates, to delete a specific record (RFC 2136).
+    NONE,
+    /// Matches any class in questions, and deletes whole RRsets in dynamic updates.
+    ANY,
+}
+
+impl QueryClass {
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            Self::UNKNOWN(x) => x,
+            Self::IN => 1,
+            Self::CS => 2,
+            Self::CH => 3,
+            Self::HS => 4,
+            Self::NONE => 254,
+            Self::ANY => 255,
+        }
+    }
+
+    pub fn from_num(num: u16) -> Self {
+        match num {
+            1 => Self::IN,
+            2 => Self::CS,
+            3 => Self::CH,
+            4 => Self::HS,
+            254 => Self::NONE,
+            255 => Self::ANY,
             _ => Self::UNKNOWN(num),
         }
     }
+
+    /// Parses a class mnemonic as used in master files, including the generic `CLASS123` form.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "IN" => Some(Self::IN),
+            "CS" => Some(Self::CS),
+            "CH" => Some(Self::CH),
+            "HS" => Some(Self::HS),
+            "NONE" => Some(Self::NONE),
+            "ANY" => Some(Self::ANY),
+            name => name
+                .strip_prefix("CLASS")
+                .and_then(|num| num.parse().ok())
+                .map(Self::from_num),
+        }
+    }
+}
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UNKNOWN(num) => write!(f, "CLASS{}", num),
+            other => write!(f, "{:?}", other),
+        }
+    }
 }
 
-pub struct BytePacketBuffer {
-    buf: Vec<u8>, // TODO: change write methods to push to buf instead of setting buf[pos]
-    // directly. Remove "pos"
+/// Bounds-checked cursor over a received message. Every read past the end of the data is an
+/// error rather than a panic, since the bytes usually come straight from the network.
+pub struct BytePacketReader<'a> {
+    buf: &'a [u8],
     pos: usize,
 }
 
-impl BytePacketBuffer {
-    fn new() -> Self {
-        BytePacketBuffer {
-            buf: vec![0; BUF_LEN],
-            pos: 0,
-        }
+impl<'a> BytePacketReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        BytePacketReader { buf, pos: 0 }
+    }
+
+    fn pos(&self) -> usize {
+        self.pos
     }
 
-    fn step(&mut self, steps: usize) {
-        self.pos += steps
+    pub fn step(&mut self, steps: usize) -> Result<()> {
+        self.seek(self.pos + steps)
     }
 
-    fn seek(&mut self, pos: usize) {
-        self.pos = pos
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > self.buf.len() {
+            return Err(anyhow!("Position {} is past the end of the buffer", pos));
+        }
+        self.pos = pos;
+
+        Ok(())
     }
 
     fn read(&mut self) -> Result<u8> {
-        let res = self.buf[self.pos];
+        let res = self.get(self.pos)?;
         self.pos += 1;
 
         Ok(res)
     }
 
     fn get(&self, pos: usize) -> Result<u8> {
-        Ok(self.buf[pos])
+        self.buf
+            .get(pos)
+            .copied()
+            .ok_or_else(|| anyhow!("Position {} is past the end of the buffer", pos))
+    }
+
+    fn get_range(&self, start: usize, len: usize) -> Result<&'a [u8]> {
+        self.buf.get(start..start + len).ok_or_else(|| {
+            anyhow!(
+                "Range {}..{} is past the end of the buffer",
+                start,
+                start + len
+            )
+        })
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let res = self.get_range(self.pos, len)?;
+        self.pos += len;
+
+        Ok(res)
     }
 
-    fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
-        Ok(&self.buf[start..start + len])
+    /// Reads whatever is left of a record's data, up to `end`.
+    fn read_to(&mut self, end: usize) -> Result<&'a [u8]> {
+        let len = end
+            .checked_sub(self.pos)
+            .ok_or_else(|| anyhow!("Record data overrun"))?;
+
+        self.read_bytes(len)
     }
 
     fn read_u16(&mut self) -> Result<u16> {
@@ -113,7 +502,18 @@ impl BytePacketBuffer {
         Ok((self.read_u16()? as u32) << 16 | (self.read_u16()? as u32))
     }
 
+    /// Reads a name, lowercased the way names are kept everywhere.
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+        self.read_name(outstr, true)
+    }
+
+    /// Reads a name in the case it was sent in, for the few that have to keep it, like the next
+    /// name of NSEC records (RFC 6840 section 5.1).
+    fn read_qname_as_sent(&mut self, outstr: &mut String) -> Result<()> {
+        self.read_name(outstr, false)
+    }
+
+    fn read_name(&mut self, outstr: &mut String, lowercase: bool) -> Result<()> {
         let mut pos = self.pos;
         let mut jumped = false;
         let max_jumps = 5;
@@ -129,7 +529,7 @@ impl BytePacketBuffer {
 
             if (len & 0xC0) == 0xC0 {
                 if !jumped {
-                    self.seek(pos + 2);
+                    self.seek(pos + 2)?;
                 }
 
                 let b2 = self.get(pos + 1)? as u16;
@@ -152,7 +552,12 @@ impl BytePacketBuffer {
 
             let str_buffer = self.get_range(pos, len as usize)?;
 
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            let label = String::from_utf8_lossy(str_buffer);
+            if lowercase {
+                outstr.push_str(&label.to_lowercase());
+            } else {
+                outstr.push_str(&label);
+            }
 
             delim = ".";
 
@@ -160,15 +565,62 @@ impl BytePacketBuffer {
         }
 
         if !jumped {
-            self.seek(pos);
+            self.seek(pos)?;
         }
 
         Ok(())
     }
+}
+
+/// Growable buffer that messages are serialized into. It refuses to grow past what can be sent
+/// over TCP.
+pub struct BytePacketWriter {
+    buf: Vec<u8>,
+    /// Offsets of the names written so far, keyed by every suffix of them, so repeated suffixes
+    /// can be replaced by a pointer. `None` when compression is disabled.
+    names: Option<HashMap<String, usize>>,
+}
+
+impl BytePacketWriter {
+    fn new() -> Self {
+        BytePacketWriter {
+            buf: Vec::with_capacity(DEFAULT_UDP_PAYLOAD_SIZE),
+            names: Some(HashMap::new()),
+        }
+    }
+
+    /// A writer that always spells names out in full, as required for the canonical form of
+    /// records that DNSSEC signatures are computed over.
+    pub fn without_compression() -> Self {
+        BytePacketWriter {
+            buf: Vec::with_capacity(DEFAULT_UDP_PAYLOAD_SIZE),
+            names: None,
+        }
+    }
+
+    fn pos(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Drops everything written from `pos` onwards.
+    fn truncate(&mut self, pos: usize) {
+        self.buf.truncate(pos);
+
+        // Pointers into the discarded bytes would point at garbage
+        if let Some(names) = self.names.as_mut() {
+            names.retain(|_, offset| *offset < pos);
+        }
+    }
 
     fn write(&mut self, val: u8) -> Result<()> {
-        self.buf[self.pos] = val;
-        self.pos += 1;
+        if self.buf.len() >= MAX_PACKET_LEN {
+            return Err(anyhow!("Message exceeds {} bytes", MAX_PACKET_LEN));
+        }
+        self.buf.push(val);
 
         Ok(())
     }
@@ -186,34 +638,94 @@ impl BytePacketBuffer {
 
     fn write_u32(&mut self, val: u32) -> Result<()> {
         self.write_u16((val >> 16) as u16)?;
-        self.write_u16((val & 0xFF) as u16)?;
+        self.write_u16((val & 0xFFFF) as u16)?;
+
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for b in bytes {
+            self.write(*b)?;
+        }
 
         Ok(())
     }
 
+    /// Writes a name, replacing the longest suffix that was already written by a pointer to it.
+    /// Only owner names and the names inside RFC 1035 record types may be compressed.
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x3f {
-                return Err(anyhow!("Single label exceeds 63 characters"));
+        let mut rest = qname.trim_end_matches('.');
+
+        while !rest.is_empty() {
+            if let Some(names) = self.names.as_mut() {
+                let suffix = rest.to_ascii_lowercase();
+                if let Some(offset) = names.get(&suffix).copied() {
+                    return self.write_u16(0xC000 | offset as u16);
+                }
+
+                // Pointers only have 14 bits for the offset
+                if self.buf.len() <= 0x3FFF {
+                    names.insert(suffix, self.buf.len());
+                }
             }
 
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
-                self.write_u8(*b)?;
+            let (label, next) = rest.split_once('.').unwrap_or((rest, ""));
+            self.write_label(label)?;
+            rest = next;
+        }
+
+        self.write_u8(0)
+    }
+
+    /// Writes a name in full, for RDATA that must not be compressed (RFC 2782, RFC 3597).
+    fn write_qname_uncompressed(&mut self, qname: &str) -> Result<()> {
+        let rest = qname.trim_end_matches('.');
+        if !rest.is_empty() {
+            for label in rest.split('.') {
+                self.write_label(label)?;
             }
         }
 
         self.write_u8(0)
     }
 
-    fn set(&mut self, pos: usize, val: u8) {
-        self.buf[pos] = val;
+    /// Writes a <character-string>, a length byte followed by at most 255 bytes of data.
+    fn write_character_string(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > 0xFF {
+            return Err(anyhow!("Character string exceeds 255 bytes"));
+        }
+
+        self.write_u8(data.len() as u8)?;
+        self.write_bytes(data)
+    }
+
+    fn write_label(&mut self, label: &str) -> Result<()> {
+        let len = label.len();
+        if len == 0 {
+            return Err(anyhow!("Empty label in name"));
+        }
+        if len > 0x3f {
+            return Err(anyhow!("Single label exceeds 63 characters"));
+        }
+
+        self.write_u8(len as u8)?;
+        self.write_bytes(label.as_bytes())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        match self.buf.get_mut(pos) {
+            Some(b) => *b = val,
+            None => return Err(anyhow!("Position {} has not been written yet", pos)),
+        }
+
+        Ok(())
     }
 
-    fn set_u16(&mut self, pos: usize, val: u16) {
-        self.set(pos, (val << 8) as u8);
-        self.set(pos + 1, (val & 0xFF) as u8);
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
+
+        Ok(())
     }
 }
 
@@ -263,7 +775,7 @@ impl DnsHeader {
         Self::default()
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn read(&mut self, buffer: &mut BytePacketReader) -> Result<()> {
         self.id = buffer.read_u16()?;
         let flags = buffer.read_u16()?;
         let a = (flags >> 8) as u8;
@@ -277,6 +789,7 @@ impl DnsHeader {
         self.checking_disabled = (b & (1 << 4)) > 0;
         self.authed_data = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
+        self.recursion_available = (b & (1 << 7)) > 0;
 
         self.questions = buffer.read_u16()?;
         self.answers = buffer.read_u16()?;
@@ -286,20 +799,20 @@ impl DnsHeader {
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+    pub fn write(&self, buffer: &mut BytePacketWriter) -> Result<usize> {
         buffer.write_u16(self.id)?;
 
-        let initial_pos = buffer.pos;
+        let initial_pos = buffer.pos();
         buffer.write_u8(
             (self.recursion_desired as u8)
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
                 | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8,
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write_u8(
-            (self.response as u8)
+            (self.rescode as u8)
                 | ((self.checking_disabled as u8) << 4)
                 | ((self.authed_data as u8) << 5)
                 | ((self.z as u8) << 6)
@@ -319,26 +832,31 @@ impl DnsHeader {
 pub struct DnsQuestion {
     name: String,
     qtype: QueryType,
+    qclass: QueryClass,
 }
 
 impl DnsQuestion {
-    pub fn new(name: String, qtype: QueryType) -> Self {
-        Self { name, qtype }
+    pub fn new(name: String, qtype: QueryType, qclass: QueryClass) -> Self {
+        Self {
+            name,
+            qtype,
+            qclass,
+        }
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn read(&mut self, buffer: &mut BytePacketReader) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?);
-        let _ = buffer.read_u16()?;
+        self.qclass = QueryClass::from_num(buffer.read_u16()?);
 
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn write(&self, buffer: &mut BytePacketWriter) -> Result<()> {
         buffer.write_qname(&self.name)?;
 
         buffer.write_u16(self.qtype.to_num())?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass.to_num())?;
 
         Ok(())
     }
@@ -346,63 +864,189 @@ impl DnsQuestion {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum DnsRecord {
+    /// A record of a type we have no parser for. Its RDATA is kept as they came, so
+    /// that it can be passed on unchanged (RFC 3597).
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
+        class: QueryClass,
         ttl: u32,
     },
     A {
         domain: String,
         addr: Ipv4Addr,
+        class: QueryClass,
         ttl: u32,
     },
     NS {
         domain: String,
         host: String,
+        class: QueryClass,
         ttl: u32,
     },
     CNAME {
         domain: String,
         host: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        class: QueryClass,
+        ttl: u32,
+    },
+    PTR {
+        domain: String,
+        host: String,
+        class: QueryClass,
         ttl: u32,
     },
     MX {
         domain: String,
         priority: u16,
         host: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    TXT {
+        domain: String,
+        strings: Vec<Vec<u8>>,
+        class: QueryClass,
         ttl: u32,
     },
     AAAA {
         domain: String,
         addr: Ipv6Addr,
+        class: QueryClass,
+        ttl: u32,
+    },
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        class: QueryClass,
         ttl: u32,
     },
 }
 
 impl DnsRecord {
-    pub fn read(buffer: &mut BytePacketBuffer) -> Result<Self> {
+    pub fn read(buffer: &mut BytePacketReader) -> Result<Self> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?; // This is the class, which is always 1 for internet
+        let class = QueryClass::from_num(buffer.read_u16()?);
 
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+        let end = buffer.pos() + data_len as usize;
+
+        // Dynamic updates delete with records of class ANY or NONE and no RDATA, which none of
+        // the typed variants could hold (RFC 2136 section 2.5)
+        if data_len == 0 && matches!(class, QueryClass::ANY | QueryClass::NONE) {
+            return Ok(Self::UNKNOWN {
+                domain,
+                qtype: qtype_num,
+                data: Vec::new(),
+                class,
+                ttl,
+            });
+        }
 
-        match qtype {
+        let record = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
 
-                Ok(Self::A { domain, addr, ttl })
+                Self::A {
+                    domain,
+                    addr,
+                    class,
+                    ttl,
+                }
             }
             QueryType::AAAA => {
                 let raw_addr1 = buffer.read_u32()?;
@@ -412,92 +1056,295 @@ impl DnsRecord {
 
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
                 );
 
-                Ok(Self::AAAA { domain, addr, ttl })
+                Self::AAAA {
+                    domain,
+                    addr,
+                    class,
+                    ttl,
+                }
             }
             QueryType::NS => {
                 let mut ns = String::new();
                 buffer.read_qname(&mut ns)?;
 
-                Ok(Self::NS {
+                Self::NS {
                     domain,
                     host: ns,
+                    class,
                     ttl,
-                })
+                }
             }
             QueryType::CNAME => {
                 let mut host = String::new();
                 buffer.read_qname(&mut host)?;
 
-                Ok(Self::CNAME { domain, host, ttl })
+                Self::CNAME {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                Self::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    class,
+                    ttl,
+                }
             }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
                 buffer.read_qname(&mut mx)?;
 
-                Ok(Self::MX {
+                Self::MX {
                     domain,
                     priority,
                     host: mx,
+                    class,
                     ttl,
-                })
+                }
             }
-            QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize);
-                Ok(Self::UNKNOWN {
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Self::PTR {
                     domain,
-                    qtype: qtype_num,
-                    data_len,
+                    host,
+                    class,
                     ttl,
-                })
+                }
             }
-        }
-    }
+            QueryType::TXT => {
+                let mut strings = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read()? as usize;
+                    strings.push(buffer.read_bytes(len)?.to_vec());
+                }
 
-    fn write_record(
-        buffer: &mut BytePacketBuffer,
-        qtype: QueryType,
-        domain: &String,
-        host: &String,
-        ttl: u32,
-    ) -> Result<()> {
-        buffer.write_qname(domain)?;
-        buffer.write_u16(qtype.to_num())?;
-        buffer.write_u16(1)?;
-        buffer.write_u32(ttl)?;
+                Self::TXT {
+                    domain,
+                    strings,
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
 
-        let pos = buffer.pos;
-        buffer.write_u16(0)?;
+                Self::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::CAA => {
+                let flags = buffer.read()?;
+                let tag_len = buffer.read()? as usize;
+                let value_len = (data_len as usize)
+                    .checked_sub(2 + tag_len)
+                    .ok_or_else(|| anyhow!("CAA tag overruns the record data"))?;
+                let tag = String::from_utf8_lossy(buffer.read_bytes(tag_len)?).into_owned();
+                let value = buffer.read_bytes(value_len)?.to_vec();
+
+                Self::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::DS => Self::DS {
+                domain,
+                key_tag: buffer.read_u16()?,
+                algorithm: buffer.read()?,
+                digest_type: buffer.read()?,
+                digest: buffer.read_to(end)?.to_vec(),
+                class,
+                ttl,
+            },
+            QueryType::RRSIG => {
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read()?;
+                let labels = buffer.read()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer_name = String::new();
+                buffer.read_qname(&mut signer_name)?;
+
+                Self::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature: buffer.read_to(end)?.to_vec(),
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::NSEC => {
+                let mut next_domain = String::new();
+                buffer.read_qname_as_sent(&mut next_domain)?;
+
+                Self::NSEC {
+                    domain,
+                    next_domain,
+                    types: dnssec::decode_type_bitmap(buffer.read_to(end)?)?,
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::DNSKEY => Self::DNSKEY {
+                domain,
+                flags: buffer.read_u16()?,
+                protocol: buffer.read()?,
+                algorithm: buffer.read()?,
+                public_key: buffer.read_to(end)?.to_vec(),
+                class,
+                ttl,
+            },
+            QueryType::NSEC3 => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+                let salt = buffer.read_bytes(salt_len)?.to_vec();
+                let hash_len = buffer.read()? as usize;
+                let next_hashed = buffer.read_bytes(hash_len)?.to_vec();
+
+                Self::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types: dnssec::decode_type_bitmap(buffer.read_to(end)?)?,
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::NSEC3PARAM => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+
+                Self::NSEC3PARAM {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt: buffer.read_bytes(salt_len)?.to_vec(),
+                    class,
+                    ttl,
+                }
+            }
+            QueryType::UNKNOWN(_)
+            | QueryType::OPT
+            | QueryType::IXFR
+            | QueryType::AXFR
+            | QueryType::ANY => {
+                let data = buffer.read_bytes(data_len as usize)?.to_vec();
+
+                Self::UNKNOWN {
+                    domain,
+                    qtype: qtype_num,
+                    data,
+                    class,
+                    ttl,
+                }
+            }
+        };
+
+        // Fixed layouts don't look at RDLENGTH, so check they took up exactly what it says
+        if buffer.pos() != end {
+            return Err(anyhow!(
+                "{} record data doesn't match its length of {} bytes",
+                qtype,
+                data_len
+            ));
+        }
+
+        Ok(record)
+    }
+
+    fn write_record(
+        buffer: &mut BytePacketWriter,
+        qtype: QueryType,
+        domain: &str,
+        host: &str,
+        class: QueryClass,
+        ttl: u32,
+    ) -> Result<()> {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(qtype.to_num())?;
+        buffer.write_u16(class.to_num())?;
+        buffer.write_u32(ttl)?;
+
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
 
         buffer.write_qname(host)?;
 
-        let size = buffer.pos - (pos + 2);
-        buffer.set_u16(pos, size as u16);
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
 
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
-        let start_pos = buffer.pos;
+    pub fn write(&self, buffer: &mut BytePacketWriter) -> Result<usize> {
+        let start_pos = buffer.pos();
 
         match *self {
             Self::A {
                 ref domain,
                 ref addr,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -508,13 +1355,14 @@ impl DnsRecord {
             Self::AAAA {
                 ref domain,
                 ref addr,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
-                buffer.write_u16(4)?;
+                buffer.write_u16(16)?;
 
                 for oc in addr.segments() {
                     buffer.write_u16(oc)?;
@@ -523,41 +1371,305 @@ impl DnsRecord {
             Self::NS {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
-                Self::write_record(buffer, QueryType::NS, domain, host, ttl)?;
+                Self::write_record(buffer, QueryType::NS, domain, host, class, ttl)?;
             }
             Self::CNAME {
                 ref domain,
                 ref host,
+                class,
+                ttl,
+            } => {
+                Self::write_record(buffer, QueryType::CNAME, domain, host, class, ttl)?;
+            }
+            Self::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                class,
                 ttl,
             } => {
-                Self::write_record(buffer, QueryType::CNAME, domain, host, ttl)?;
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             }
             Self::MX {
                 ref domain,
                 priority,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
-                let pos = buffer.pos;
+                let pos = buffer.pos();
                 buffer.write_u16(0)?;
 
                 buffer.write_u16(priority)?;
                 buffer.write_qname(host)?;
 
-                let size = buffer.pos - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Self::PTR {
+                ref domain,
+                ref host,
+                class,
+                ttl,
+            } => {
+                Self::write_record(buffer, QueryType::PTR, domain, host, class, ttl)?;
+            }
+            Self::TXT {
+                ref domain,
+                ref strings,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for string in strings {
+                    buffer.write_character_string(string)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Self::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Self::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((2 + tag.len() + value.len()) as u16)?;
+
+                buffer.write_u8(flags)?;
+                buffer.write_character_string(tag.as_bytes())?;
+                buffer.write_bytes(value)?;
+            }
+            Self::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((4 + digest.len()) as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                buffer.write_bytes(digest)?;
+            }
+            Self::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(type_covered.to_num())?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_qname_uncompressed(signer_name)?;
+                buffer.write_bytes(signature)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Self::NSEC {
+                ref domain,
+                ref next_domain,
+                ref types,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname_uncompressed(next_domain)?;
+                buffer.write_bytes(&dnssec::encode_type_bitmap(types))?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Self::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((4 + public_key.len()) as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(public_key)?;
+            }
+            Self::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_character_string(salt)?;
+                buffer.write_character_string(next_hashed)?;
+                buffer.write_bytes(&dnssec::encode_type_bitmap(types))?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Self::NSEC3PARAM {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3PARAM.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((5 + salt.len()) as u16)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_character_string(salt)?;
+            }
+            Self::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
-            Self::UNKNOWN { .. } => println!("Skipping record: {:?}", self),
         }
 
-        Ok(buffer.pos - start_pos)
+        Ok(buffer.pos() - start_pos)
     }
 
     pub fn domain(&self) -> String {
@@ -566,9 +1678,21 @@ impl DnsRecord {
             Self::AAAA { domain, .. } => domain,
             Self::NS { domain, .. } => domain,
             Self::CNAME { domain, .. } => domain,
+            Self::SOA { domain, .. } => domain,
+            Self::PTR { domain, .. } => domain,
             Self::MX { domain, .. } => domain,
+            Self::TXT { domain, .. } => domain,
+            Self::SRV { domain, .. } => domain,
+            Self::DS { domain, .. } => domain,
+            Self::RRSIG { domain, .. } => domain,
+            Self::NSEC { domain, .. } => domain,
+            Self::DNSKEY { domain, .. } => domain,
+            Self::NSEC3 { domain, .. } => domain,
+            Self::NSEC3PARAM { domain, .. } => domain,
+            Self::CAA { domain, .. } => domain,
             Self::UNKNOWN { domain, .. } => domain,
-        }.clone()
+        }
+        .clone()
     }
 
     pub fn ttl(&self) -> u32 {
@@ -577,10 +1701,457 @@ impl DnsRecord {
             Self::AAAA { ttl, .. } => *ttl,
             Self::NS { ttl, .. } => *ttl,
             Self::CNAME { ttl, .. } => *ttl,
+            Self::SOA { ttl, .. } => *ttl,
+            Self::PTR { ttl, .. } => *ttl,
             Self::MX { ttl, .. } => *ttl,
+            Self::TXT { ttl, .. } => *ttl,
+            Self::SRV { ttl, .. } => *ttl,
+            Self::DS { ttl, .. } => *ttl,
+            Self::RRSIG { ttl, .. } => *ttl,
+            Self::NSEC { ttl, .. } => *ttl,
+            Self::DNSKEY { ttl, .. } => *ttl,
+            Self::NSEC3 { ttl, .. } => *ttl,
+            Self::NSEC3PARAM { ttl, .. } => *ttl,
+            Self::CAA { ttl, .. } => *ttl,
             Self::UNKNOWN { ttl, .. } => *ttl,
         }
     }
+
+    pub fn class(&self) -> QueryClass {
+        match self {
+            Self::A { class, .. }
+            | Self::AAAA { class, .. }
+            | Self::NS { class, .. }
+            | Self::CNAME { class, .. }
+            | Self::SOA { class, .. }
+            | Self::PTR { class, .. }
+            | Self::MX { class, .. }
+            | Self::TXT { class, .. }
+            | Self::SRV { class, .. }
+            | Self::DS { class, .. }
+            | Self::RRSIG { class, .. }
+            | Self::NSEC { class, .. }
+            | Self::DNSKEY { class, .. }
+            | Self::NSEC3 { class, .. }
+            | Self::NSEC3PARAM { class, .. }
+            | Self::CAA { class, .. }
+            | Self::UNKNOWN { class, .. } => *class,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            Self::A { ttl, .. }
+            | Self::AAAA { ttl, .. }
+            | Self::NS { ttl, .. }
+            | Self::CNAME { ttl, .. }
+            | Self::SOA { ttl, .. }
+            | Self::PTR { ttl, .. }
+            | Self::MX { ttl, .. }
+            | Self::TXT { ttl, .. }
+            | Self::SRV { ttl, .. }
+            | Self::DS { ttl, .. }
+            | Self::RRSIG { ttl, .. }
+            | Self::NSEC { ttl, .. }
+            | Self::DNSKEY { ttl, .. }
+            | Self::NSEC3 { ttl, .. }
+            | Self::NSEC3PARAM { ttl, .. }
+            | Self::CAA { ttl, .. }
+            | Self::UNKNOWN { ttl, .. } => *ttl = new_ttl,
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: String) {
+        match self {
+            Self::A { domain, .. }
+            | Self::AAAA { domain, .. }
+            | Self::NS { domain, .. }
+            | Self::CNAME { domain, .. }
+            | Self::SOA { domain, .. }
+            | Self::PTR { domain, .. }
+            | Self::MX { domain, .. }
+            | Self::TXT { domain, .. }
+            | Self::SRV { domain, .. }
+            | Self::DS { domain, .. }
+            | Self::RRSIG { domain, .. }
+            | Self::NSEC { domain, .. }
+            | Self::DNSKEY { domain, .. }
+            | Self::NSEC3 { domain, .. }
+            | Self::NSEC3PARAM { domain, .. }
+            | Self::CAA { domain, .. }
+            | Self::UNKNOWN { domain, .. } => *domain = new_domain,
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            Self::A { .. } => QueryType::A,
+            Self::AAAA { .. } => QueryType::AAAA,
+            Self::NS { .. } => QueryType::NS,
+            Self::CNAME { .. } => QueryType::CNAME,
+            Self::SOA { .. } => QueryType::SOA,
+            Self::PTR { .. } => QueryType::PTR,
+            Self::MX { .. } => QueryType::MX,
+            Self::TXT { .. } => QueryType::TXT,
+            Self::SRV { .. } => QueryType::SRV,
+            Self::DS { .. } => QueryType::DS,
+            Self::RRSIG { .. } => QueryType::RRSIG,
+            Self::NSEC { .. } => QueryType::NSEC,
+            Self::DNSKEY { .. } => QueryType::DNSKEY,
+            Self::NSEC3 { .. } => QueryType::NSEC3,
+            Self::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            Self::CAA { .. } => QueryType::CAA,
+            Self::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+        }
+    }
+
+    /// The record in the canonical form signatures are computed over (RFC 4034 section 6.2):
+    /// no compression, the TTL the RRSIG was made with, and the owner and the names in the
+    /// RDATA of the types listed there lowercased. The next name of NSEC records is no longer
+    /// among them, and keeps its case (RFC 6840 section 5.1).
+    pub fn to_canonical(&self, original_ttl: u32) -> Result<Vec<u8>> {
+        let mut record = self.clone();
+        record.set_ttl(original_ttl);
+        record.set_domain(record.domain().to_ascii_lowercase());
+        match &mut record {
+            Self::NS { host, .. }
+            | Self::CNAME { host, .. }
+            | Self::PTR { host, .. }
+            | Self::MX { host, .. }
+            | Self::SRV { host, .. } => host.make_ascii_lowercase(),
+            Self::SOA { mname, rname, .. } => {
+                mname.make_ascii_lowercase();
+                rname.make_ascii_lowercase();
+            }
+            Self::RRSIG { signer_name, .. } => signer_name.make_ascii_lowercase(),
+            _ => {}
+        }
+
+        let mut buffer = BytePacketWriter::without_compression();
+        record.write(&mut buffer)?;
+
+        Ok(buffer.buf)
+    }
+
+    /// An RRset the way it is signed: every record in canonical form, ordered by RDATA and
+    /// without duplicates (RFC 4034 section 6.3).
+    pub fn canonical_rrset(rrset: &[DnsRecord], original_ttl: u32) -> Result<Vec<u8>> {
+        let mut records = rrset
+            .iter()
+            .map(|rec| {
+                // The RDATA follows the owner name, then type, class, TTL and RDLENGTH
+                let owner_len = match rec.domain().len() {
+                    0 => 1,
+                    len => len + 2,
+                };
+                Ok((owner_len + 10, rec.to_canonical(original_ttl)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        records.sort_by(|(a_start, a), (b_start, b)| a[*a_start..].cmp(&b[*b_start..]));
+        records.dedup_by(|(_, a), (_, b)| a == b);
+
+        Ok(records.into_iter().flat_map(|(_, wire)| wire).collect())
+    }
+}
+
+/// A name the way master files write it, absolute with its trailing dot.
+struct Fqdn<'a>(&'a str);
+
+impl fmt::Display for Fqdn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.", self.0)
+    }
+}
+
+/// A <character-string> in quotes, with quotes, backslashes and unprintable bytes escaped.
+struct CharacterString<'a>(&'a [u8]);
+
+impl fmt::Display for CharacterString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for &byte in self.0 {
+            match byte {
+                b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+                0x20..=0x7E => write!(f, "{}", byte as char)?,
+                _ => write!(f, "\\{:03}", byte)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// An NSEC3 salt in hex, or `-` when there is none.
+struct Salt<'a>(&'a [u8]);
+
+impl fmt::Display for Salt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            [] => write!(f, "-"),
+            salt => write!(f, "{}", HEXUPPER.encode(salt)),
+        }
+    }
+}
+
+/// Presentation format, one line as it would appear in a master file.
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} ",
+            Fqdn(&self.domain()),
+            self.ttl(),
+            self.class(),
+            self.qtype()
+        )?;
+
+        match self {
+            Self::A { addr, .. } => write!(f, "{}", addr),
+            Self::AAAA { addr, .. } => write!(f, "{}", addr),
+            Self::NS { host, .. } | Self::CNAME { host, .. } | Self::PTR { host, .. } => {
+                write!(f, "{}", Fqdn(host))
+            }
+            Self::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                Fqdn(mname),
+                Fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            Self::MX { priority, host, .. } => write!(f, "{} {}", priority, Fqdn(host)),
+            Self::TXT { strings, .. } => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", CharacterString(string))?;
+                }
+                Ok(())
+            }
+            Self::SRV {
+                priority,
+                weight,
+                port,
+                host,
+                ..
+            } => write!(f, "{} {} {} {}", priority, weight, port, Fqdn(host)),
+            Self::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                HEXUPPER.encode(digest)
+            ),
+            Self::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                dnssec::Timestamp(*expiration),
+                dnssec::Timestamp(*inception),
+                key_tag,
+                Fqdn(signer_name),
+                BASE64.encode(signature)
+            ),
+            Self::NSEC {
+                next_domain, types, ..
+            } => {
+                write!(f, "{}", Fqdn(next_domain))?;
+                for qtype in types {
+                    write!(f, " {}", qtype)?;
+                }
+                Ok(())
+            }
+            Self::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                BASE64.encode(public_key)
+            ),
+            Self::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => {
+                write!(
+                    f,
+                    "{} {} {} {} {}",
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    Salt(salt),
+                    BASE32HEX_NOPAD.encode(next_hashed)
+                )?;
+                for qtype in types {
+                    write!(f, " {}", qtype)?;
+                }
+                Ok(())
+            }
+            Self::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                Salt(salt)
+            ),
+            Self::CAA {
+                flags, tag, value, ..
+            } => write!(f, "{} {} {}", flags, tag, CharacterString(value)),
+            // The generic form of RFC 3597, the length followed by the data in hex
+            Self::UNKNOWN { data, .. } => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                for byte in data {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The EDNS(0) OPT pseudo-record (RFC 6891). It lives in the additional section on the wire,
+/// but it describes the message rather than a domain, so it is kept apart from the resources.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Reads the rest of an OPT record, starting right after its owner name and type.
+    pub fn read(buffer: &mut BytePacketReader) -> Result<Self> {
+        let udp_payload_size = buffer.read_u16()?;
+        let extended_rcode = buffer.read()?;
+        let version = buffer.read()?;
+        let flags = buffer.read_u16()?;
+        let data_len = buffer.read_u16()? as usize;
+
+        let end = buffer.pos() + data_len;
+        let mut options = Vec::new();
+        while buffer.pos() < end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let data = buffer.read_bytes(len)?.to_vec();
+
+            options.push(EdnsOption { code, data });
+        }
+
+        Ok(Self {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok: (flags & 0x8000) > 0,
+            options,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketWriter) -> Result<()> {
+        buffer.write_u8(0)?; // The owner of an OPT record is always the root
+        buffer.write_u16(QueryType::OPT.to_num())?;
+        buffer.write_u16(self.udp_payload_size)?;
+        buffer.write_u8(self.extended_rcode)?;
+        buffer.write_u8(self.version)?;
+        buffer.write_u16(if self.dnssec_ok { 0x8000 } else { 0 })?;
+
+        let data_len: usize = self.options.iter().map(|opt| 4 + opt.data.len()).sum();
+        buffer.write_u16(data_len as u16)?;
+
+        for opt in &self.options {
+            buffer.write_u16(opt.code)?;
+            buffer.write_u16(opt.data.len() as u16)?;
+            for b in &opt.data {
+                buffer.write_u8(*b)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Size of the whole OPT record on the wire.
+    pub fn wire_len(&self) -> usize {
+        let data_len: usize = self.options.iter().map(|opt| 4 + opt.data.len()).sum();
+        11 + data_len
+    }
+
+    /// The largest UDP response the sender of this record is able to receive. Values below 512
+    /// must be treated as 512.
+    pub fn max_payload(&self) -> usize {
+        (self.udp_payload_size as usize).max(DEFAULT_UDP_PAYLOAD_SIZE)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -590,6 +2161,7 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
+    pub edns: Option<Edns>,
 }
 
 impl Default for DnsPacket {
@@ -600,6 +2172,7 @@ impl Default for DnsPacket {
             answers: Vec::new(),
             authorities: Vec::new(),
             resources: Vec::new(),
+            edns: None,
         }
     }
 }
@@ -609,12 +2182,13 @@ impl DnsPacket {
         Self::default()
     }
 
-    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<Self> {
+    pub fn from_buffer(buffer: &mut BytePacketReader) -> Result<Self> {
         let mut result = Self::new();
         result.header.read(buffer)?;
 
         for _ in 0..result.header.questions {
-            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
+            let mut question =
+                DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0), QueryClass::IN);
             question.read(buffer)?;
             result.questions.push(question);
         }
@@ -630,6 +2204,17 @@ impl DnsPacket {
         }
 
         for _ in 0..result.header.resource_entries {
+            // Peek at the type, so the OPT pseudo-record can be pulled out of the resources
+            let record_start = buffer.pos();
+            let mut domain = String::new();
+            buffer.read_qname(&mut domain)?;
+
+            if QueryType::from_num(buffer.read_u16()?) == QueryType::OPT {
+                result.edns = Some(Edns::read(buffer)?);
+                continue;
+            }
+
+            buffer.seek(record_start)?;
             let rec = DnsRecord::read(buffer)?;
             result.resources.push(rec);
         }
@@ -637,140 +2222,547 @@ impl DnsPacket {
         Ok(result)
     }
 
-    pub fn write(&mut self, buffer: &mut BytePacketBuffer, is_udp: bool) -> Result<()> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+    /// Writes as many whole RRsets as fit below `limit`, returning how many records were
+    /// written. An RRset that only partly fits is left out altogether (RFC 2181 section 9).
+    fn write_records(
+        records: &[DnsRecord],
+        buffer: &mut BytePacketWriter,
+        limit: usize,
+    ) -> Result<usize> {
+        let mut rrset_start = (0, buffer.pos());
+        for (written, rec) in records.iter().enumerate() {
+            let record_start = buffer.pos();
+            let same_rrset = written > 0 && {
+                let last = &records[written - 1];
+                last.domain() == rec.domain()
+                    && last.qtype() == rec.qtype()
+                    && last.class() == rec.class()
+            };
+            if !same_rrset {
+                rrset_start = (written, record_start);
+            }
+
+            if let Err(e) = rec.write(buffer) {
+                // Running into the largest message size is just another way of not fitting
+                if buffer.pos() < MAX_PACKET_LEN {
+                    return Err(e);
+                }
+            }
+
+            if buffer.pos() > limit || buffer.pos() >= MAX_PACKET_LEN {
+                let (written, start) = rrset_start;
+                buffer.truncate(start);
+                return Ok(written);
+            }
+        }
 
-        let header_pos = self.header.write(buffer)?;
+        Ok(records.len())
+    }
+
+    /// Writes the packet, trimmed to whole records that fit in `max_size` bytes. If an answer or
+    /// authority record had to be left out, the message is flagged as truncated so the client
+    /// can retry over TCP. Dropping additional records alone does not warrant that (RFC 2181).
+    pub fn write(&mut self, buffer: &mut BytePacketWriter, max_size: usize) -> Result<()> {
+        let start = buffer.pos();
+        // Room for the OPT record is reserved up front, since it must never be left out
+        let limit = start + max_size.saturating_sub(self.edns.as_ref().map_or(0, Edns::wire_len));
+
+        self.header.questions = self.questions.len() as u16;
+        self.header.write(buffer)?;
 
         for question in &self.questions {
             question.write(buffer)?;
         }
 
-        for rec in &self.answers {
-            rec.write(buffer)?;
-        }
+        let answers = Self::write_records(&self.answers, buffer, limit)?;
+        let mut authorities = 0;
+        let mut resources = 0;
+
+        if answers == self.answers.len() {
+            authorities = Self::write_records(&self.authorities, buffer, limit)?;
 
-        for rec in &self.authorities {
-            rec.write(buffer)?;
+            if authorities == self.authorities.len() {
+                resources = Self::write_records(&self.resources, buffer, limit)?;
+            }
         }
 
-        for rec in &self.resources {
-            rec.write(buffer)?;
+        if let Some(edns) = &self.edns {
+            edns.write(buffer)?;
         }
 
-        self.header.truncated_message = buffer.pos > 512;
-        if self.header.truncated_message && is_udp {
-            let mut old_header = buffer.get(header_pos)?;
-            old_header |= (self.header.truncated_message as u8) << 1;
-            buffer.set(header_pos, old_header);
+        self.header.truncated_message =
+            answers < self.answers.len() || authorities < self.authorities.len();
+        self.header.answers = answers as u16;
+        self.header.authoritative_entries = authorities as u16;
+        self.header.resource_entries = (resources + self.edns.is_some() as usize) as u16;
+
+        // Now that we know what made it in, go back and fix up the header
+        let mut header = BytePacketWriter::new();
+        self.header.write(&mut header)?;
+        for (i, b) in header.as_bytes().iter().enumerate() {
+            buffer.set(start + i, *b)?;
         }
 
         Ok(())
     }
 
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::A { addr, .. } => Some(*addr),
-            _ => None,
-        })
-    }
-
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&str, &str)> {
-        self.authorities
+    pub fn get_addresses(&self) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some((*addr).into()),
+                DnsRecord::AAAA { addr, .. } => Some((*addr).into()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The A and AAAA glue the additional section holds for `host`.
+    fn get_glue<'a>(&'a self, host: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+        self.resources
+            .iter()
+            .filter_map(move |record| match record {
+                DnsRecord::A { domain, addr, .. } if domain == host => Some((*addr).into()),
+                DnsRecord::AAAA { domain, addr, .. } if domain == host => Some((*addr).into()),
+                _ => None,
+            })
+    }
+
+    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.authorities
             .iter()
             .filter_map(|record| match record {
                 DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
                 _ => None,
             })
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| is_subdomain(qname, domain))
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+    /// The zone a referral for `qname` hands us down to. It has to lie strictly below `zone`, the
+    /// zone of the server that sent it, or following it would bring us no closer to an answer.
+    pub fn get_referral<'a>(&'a self, qname: &'a str, zone: &str) -> Option<&'a str> {
         self.get_ns(qname)
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
-            })
-            .map(|addr| *addr)
-            .next()
+            .map(|(domain, _)| domain)
+            .filter(|domain| *domain != zone && is_subdomain(domain, zone))
+            .max_by_key(|domain| domain.len())
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        self.get_ns(qname).map(|(_, host)| host).next()
+    /// The glue addresses of every nameserver the referral to `cut` points to.
+    pub fn get_resolved_ns(&self, cut: &str) -> Vec<IpAddr> {
+        self.get_ns(cut)
+            .filter(|(domain, _)| *domain == cut)
+            .flat_map(|(_, host)| self.get_glue(host))
+            .collect()
     }
 
-    pub fn get_cname<'a>(&'a self) -> Option<DnsRecord> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::CNAME { host, domain, ttl } => Some(DnsRecord::CNAME {
-                domain: domain.clone(),
-                host: host.clone(),
-                ttl: *ttl,
-            }),
-            _ => None,
-        })
+    pub fn get_unresolved_ns<'a>(&'a self, cut: &'a str) -> impl Iterator<Item = &'a str> {
+        self.get_ns(cut)
+            .filter(move |(domain, _)| *domain == cut)
+            .map(|(_, host)| host)
     }
 
-    pub fn final_answers(&self) -> Vec<&DnsRecord> {
+    /// Drops the records of a response that a server authoritative for `zone` has no say over,
+    /// and answers that don't belong to `qname` or the CNAME chain it starts. Whatever is left
+    /// can be trusted enough to cache.
+    pub fn sanitize(&mut self, qname: &str, zone: &str) {
+        let mut names = vec![qname.to_string()];
+        let mut answers = Vec::new();
+        let mut rest = std::mem::take(&mut self.answers);
+
+        // Each pass picks up the records of the names the previous one added to the chain
+        loop {
+            let (relevant, other): (Vec<_>, Vec<_>) = rest.into_iter().partition(|record| {
+                let domain = record.domain();
+                is_subdomain(&domain, zone) && names.contains(&domain)
+            });
+            if relevant.is_empty() {
+                break;
+            }
+
+            for record in &relevant {
+                if let DnsRecord::CNAME { host, .. } = record {
+                    names.push(host.clone());
+                }
+            }
+            answers.extend(relevant);
+            rest = other;
+        }
+        self.answers = answers;
+
+        // NS and SOA records have to be for one of the names' ancestors. The records proving
+        // a negative answer can be for any name in the zone
+        self.authorities.retain(|record| {
+            let domain = record.domain();
+            is_subdomain(&domain, zone)
+                && (names.iter().any(|name| is_subdomain(name, &domain))
+                    || matches!(
+                        record,
+                        DnsRecord::NSEC { .. } | DnsRecord::NSEC3 { .. } | DnsRecord::RRSIG { .. }
+                    ))
+        });
+        self.resources
+            .retain(|record| is_subdomain(&record.domain(), zone));
+    }
+
+    /// Follows the CNAMEs in the answer section starting from `qname`. Returns the name the chain
+    /// ends at, and how many CNAMEs it took to get there.
+    pub fn follow_cnames(&self, qname: &str) -> (String, usize) {
+        let mut name = qname.to_string();
+        let mut hops = 0;
+
+        // A chain can't be longer than the answer section, unless it loops
+        while hops < self.answers.len() {
+            let next = self.answers.iter().find_map(|record| match record {
+                DnsRecord::CNAME { domain, host, .. } if *domain == name => Some(host.clone()),
+                _ => None,
+            });
+
+            match next {
+                Some(host) => {
+                    name = host;
+                    hops += 1;
+                }
+                None => break,
+            }
+        }
+
+        (name, hops)
+    }
+
+    /// The answers that are records of type `qtype` for `qname`.
+    pub fn final_answers(&self, qname: &str, qtype: QueryType) -> Vec<&DnsRecord> {
         self.answers
             .iter()
-            .filter(|ans| match ans {
-                DnsRecord::A { .. } => true,
-                _ => false,
-            })
+            .filter(|record| record.qtype() == qtype && record.domain() == qname)
             .collect()
     }
 
+    /// The SOA of an NXDOMAIN or NODATA response. Referrals also come without answers, but they
+    /// carry NS records instead of a SOA in the authority section.
+    pub fn negative_soa(&self) -> Option<&DnsRecord> {
+        if !self.answers.is_empty()
+            || !matches!(
+                self.header.rescode,
+                ResultCode::NOERROR | ResultCode::NXDOMAIN
+            )
+        {
+            return None;
+        }
+
+        self.authorities
+            .iter()
+            .find(|rec| matches!(rec, DnsRecord::SOA { .. }))
+    }
+
+    /// The NSEC and NSEC3 records in the authority section, and the signatures over them and
+    /// the SOA, which prove a negative answer.
+    pub fn denial_proof(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.authorities.iter().filter(|record| match record {
+            DnsRecord::NSEC { .. } | DnsRecord::NSEC3 { .. } => true,
+            DnsRecord::RRSIG { type_covered, .. } => matches!(
+                type_covered,
+                QueryType::SOA | QueryType::NSEC | QueryType::NSEC3
+            ),
+            _ => false,
+        })
+    }
+
+    /// Drops the signatures and denial records a client that didn't set the DO bit has no use
+    /// for (RFC 4035 section 3.2.1), unless it asked for them by type.
+    pub fn strip_dnssec(&mut self, qtype: QueryType) {
+        let is_dnssec = |record: &DnsRecord| {
+            matches!(
+                record,
+                DnsRecord::RRSIG { .. } | DnsRecord::NSEC { .. } | DnsRecord::NSEC3 { .. }
+            )
+        };
+
+        self.answers
+            .retain(|record| record.qtype() == qtype || !is_dnssec(record));
+        self.authorities.retain(|record| !is_dnssec(record));
+        self.resources.retain(|record| !is_dnssec(record));
+    }
+
     pub fn merge(&mut self, response: Self) {
+        // Negative answers are only meaningful with the SOA that says how long to keep them. They
+        // may come after a CNAME chain, the SOA then being about the name the chain ends at
+        let negative = match response.header.rescode {
+            ResultCode::NXDOMAIN => true,
+            ResultCode::NOERROR => response
+                .answers
+                .iter()
+                .all(|record| matches!(record.qtype(), QueryType::CNAME | QueryType::RRSIG)),
+            _ => false,
+        };
+        if negative {
+            if let Some(soa) = response
+                .authorities
+                .iter()
+                .find(|record| matches!(record, DnsRecord::SOA { .. }))
+            {
+                self.authorities.push(soa.clone());
+                self.authorities.extend(response.denial_proof().cloned());
+            }
+        } else {
+            // Records expanded from a wildcard come with proof that the name they were expanded
+            // for doesn't exist (RFC 4035 section 3.1.3.3)
+            self.authorities.extend(response.denial_proof().cloned());
+        }
+
         self.answers.extend(response.answers);
         self.header.rescode = response.header.rescode;
     }
 }
 
-fn lookup(
-    qname: &str,
-    qtype: QueryType,
-    server: impl ToSocketAddrs,
-    is_udp: bool,
-    cache: &SharedDnsCache,
+/// Reads one length-prefixed message from a TCP stream.
+fn read_tcp_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut size_buf = [0u8; 2];
+    stream.read_exact(&mut size_buf)?;
+
+    let mut buf = vec![0; u16::from_be_bytes(size_buf) as usize];
+    stream.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+/// Writes one message to a TCP stream, prefixed by its length.
+fn write_tcp_message(stream: &mut TcpStream, buffer: &BytePacketWriter) -> Result<()> {
+    let len = buffer.pos();
+
+    stream.write_all(&[(len >> 8) as u8, (len & 0xFF) as u8])?;
+    stream.write_all(buffer.as_bytes())?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Whether `response` answers `request`: it has to carry the same ID and repeat the question.
+fn is_reply_to(response: &DnsPacket, request: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == request.header.id
+        && response.questions == request.questions
+}
+
+/// Sends `request` from a fresh port picked by the OS and waits for the reply. Datagrams that
+/// come from another address or don't match the request are dropped rather than trusted, since
+/// they are most likely spoofed.
+fn udp_exchange(
+    req_buf: &BytePacketWriter,
+    request: &DnsPacket,
+    source: IpAddr,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<DnsPacket> {
+    let sock = UdpSocket::bind((source, 0))?;
+    sock.send_to(req_buf.as_bytes(), server)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut res_buf = vec![0; BUF_LEN];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!("Timed out waiting for {}", server));
+        }
+        sock.set_read_timeout(Some(remaining))?;
+
+        let (len, from) = match sock.recv_from(&mut res_buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(anyhow!("Timed out waiting for {}", server));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if from != server {
+            eprintln!("Dropping reply from {}, expected {}", from, server);
+            continue;
+        }
+
+        match DnsPacket::from_buffer(&mut BytePacketReader::new(&res_buf[..len])) {
+            Ok(response) if is_reply_to(&response, request) => return Ok(response),
+            Ok(_) => eprintln!(
+                "Dropping reply from {} that doesn't match the query",
+                server
+            ),
+            Err(e) => eprintln!("Dropping malformed reply from {}: {}", server, e),
+        }
+    }
+}
+
+/// Opens a TCP connection to `server` from `source`, reads on which give up after `timeout`.
+fn tcp_connect(source: IpAddr, server: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    // std can't pick the source address of an outgoing connection, so go through socket2
+    let socket = Socket::new(
+        Domain::for_address(server),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.bind(&SocketAddr::new(source, 0).into())?;
+    socket.connect_timeout(&server.into(), timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    Ok(socket.into())
+}
+
+fn tcp_exchange(
+    req_buf: &BytePacketWriter,
+    request: &DnsPacket,
+    source: IpAddr,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<DnsPacket> {
+    let mut stream = tcp_connect(source, server, timeout)?;
+
+    write_tcp_message(&mut stream, req_buf)?;
+    let res_buf = read_tcp_message(&mut stream)?;
+
+    let response = DnsPacket::from_buffer(&mut BytePacketReader::new(&res_buf))?;
+    if !is_reply_to(&response, request) {
+        return Err(anyhow!("Reply from {} doesn't match the query", server));
+    }
+
+    Ok(response)
+}
+
+/// Sends `request` over UDP, switching to TCP when the answer comes back truncated.
+fn exchange(
+    req_buf: &BytePacketWriter,
+    request: &DnsPacket,
+    source: IpAddr,
+    server: SocketAddr,
+    timeout: Duration,
 ) -> Result<DnsPacket> {
+    let response = udp_exchange(req_buf, request, source, server, timeout)?;
+
+    // The answer didn't fit in a datagram, so ask again over TCP to get all of it
+    if response.header.truncated_message {
+        return tcp_exchange(req_buf, request, source, server, timeout);
+    }
+
+    Ok(response)
+}
+
+/// The question we send out about `qname`.
+fn query_packet(qname: &str, qtype: QueryType, ctx: &ServerContext) -> DnsPacket {
     let mut packet = DnsPacket::new();
 
-    packet.header.id = 6666;
+    packet.header.id = rand::random();
     packet.header.questions = 1;
     packet.header.recursion_desired = true;
     packet
         .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+        .push(DnsQuestion::new(qname.to_string(), qtype, QueryClass::IN));
+    let mut edns = Edns::new(EDNS_UDP_PAYLOAD_SIZE);
+    // Signatures only come back if we ask for them
+    edns.dnssec_ok = ctx.config.dnssec_validation;
+    packet.edns = Some(edns);
+
+    packet
+}
+
+/// Asks `server` about `qname`, unless the cache already knows the answer.
+fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    zone: &str,
+    ctx: &ServerContext,
+    resolution: &mut ResolutionContext,
+) -> Result<DnsPacket> {
+    let mut packet = query_packet(qname, qtype, ctx);
+
+    // An alias for the name is as good as the records themselves, the CNAME gets chased
+    let cached = {
+        let mut cache = ctx.cache.write().unwrap();
+        cache.get(qname, qtype, QueryClass::IN).or_else(|| {
+            cache
+                .get(qname, QueryType::CNAME, QueryClass::IN)
+                .filter(|entry| matches!(entry, CacheEntry::Records { .. }))
+        })
+    };
 
-    if let Some(cached) = cache.read().unwrap().get(qname) {
-        packet.answers.push(cached.clone()); // TODO: calculate remaining TTL
-        return Ok(packet);
+    match cached {
+        Some(CacheEntry::Records { records, proof }) => {
+            packet.answers.extend(records);
+            packet.authorities.extend(proof);
+            Ok(packet)
+        }
+        Some(CacheEntry::Negative(negative)) => {
+            packet.header.rescode = negative.rescode;
+            packet.authorities.push(negative.soa);
+            packet.authorities.extend(negative.proof);
+            Ok(packet)
+        }
+        None => query_server(qname, qtype, server, zone, ctx, resolution),
     }
+}
+
+/// Asks `server` about `qname`, and caches what it says. `zone` is what the server is
+/// authoritative for, and anything it says about names outside of it is thrown away before the
+/// response is cached or returned.
+fn query_server(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    zone: &str,
+    ctx: &ServerContext,
+    resolution: &mut ResolutionContext,
+) -> Result<DnsPacket> {
+    let mut packet = query_packet(qname, qtype, ctx);
 
-    let mut req_buf = BytePacketBuffer::new();
+    let source = ctx.config.source_for(&server)?;
 
-    packet.write(&mut req_buf, is_udp)?;
-    let sock = UdpSocket::bind(("0.0.0.0", 3000))?;
-    sock.send_to(&req_buf.buf[0..req_buf.pos], server)?;
+    let mut req_buf = BytePacketWriter::new();
+    packet.write(&mut req_buf, BUF_LEN)?;
 
-    let mut res_buf = BytePacketBuffer::new();
-    sock.recv_from(&mut res_buf.buf)?;
+    let timeout = ctx.config.query_timeout();
+    resolution.send_query()?;
 
-    let packet = DnsPacket::from_buffer(&mut res_buf)?;
+    let started = Instant::now();
+    let result = exchange(&req_buf, &packet, source, server, timeout);
+
+    let mut rtt = ctx.rtt.write().unwrap();
+    let mut packet = match result {
+        Ok(packet) => {
+            rtt.record(server.ip(), started.elapsed());
+            packet
+        }
+        Err(e) => {
+            rtt.record_failure(server.ip(), timeout);
+            return Err(e);
+        }
+    };
+    drop(rtt);
+
+    let received = packet.answers.len() + packet.authorities.len() + packet.resources.len();
+    packet.sanitize(qname, zone);
+    let kept = packet.answers.len() + packet.authorities.len() + packet.resources.len();
+    if kept < received {
+        eprintln!(
+            "Dropped {} records from {} outside of {}",
+            received - kept,
+            server,
+            if zone.is_empty() { "." } else { zone }
+        );
+    }
 
-    packet.answers.iter().for_each(|ans| {
-        cache
+    let proof: Vec<DnsRecord> = packet.denial_proof().cloned().collect();
+    ctx.cache
+        .write()
+        .unwrap()
+        .insert_records(&packet.answers, &proof);
+
+    if let Some(soa) = packet.negative_soa() {
+        let negative = NegativeAnswer {
+            rescode: packet.header.rescode,
+            soa: soa.clone(),
+            proof: packet.denial_proof().cloned().collect(),
+        };
+        ctx.cache
             .write()
             .unwrap()
-            .insert(ans.domain(), ans.clone(), Duration::from_secs(ans.ttl() as u64));
-    });
+            .insert_negative(qname, qtype, negative);
+    }
 
     Ok(packet)
 }
@@ -778,40 +2770,50 @@ fn lookup(
 fn recursive_lookup(
     qname: &str,
     qtype: QueryType,
-    is_udp: bool,
     accumulated_response: &mut DnsPacket,
-    cache: &SharedDnsCache,
+    ctx: &ServerContext,
+    resolution: &mut ResolutionContext,
 ) -> Result<()> {
-    // *a.root-servers.net
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+    let mut nameservers = ctx.root_servers.read().unwrap().clone();
+    // The zone the current nameservers are authoritative for
+    let mut zone = String::new();
 
     loop {
-        let server = (ns, 53);
-        let response = lookup(qname, qtype, server, is_udp, cache)?;
+        let servers = nameserver_addrs(&nameservers, ctx);
+        let response = query_servers(qname, qtype, &servers, &zone, true, ctx, resolution)?;
+
+        // Go as far down the CNAME chain as the response takes us, unless it's the CNAME itself
+        // that was asked for
+        let (target, hops) = match qtype {
+            QueryType::CNAME => (qname.to_string(), 0),
+            _ => response.follow_cnames(qname),
+        };
+        for _ in 0..hops {
+            resolution.follow_cname()?;
+        }
 
-        if !response.final_answers().is_empty() && response.header.rescode == ResultCode::NOERROR {
+        if !response.final_answers(&target, qtype).is_empty()
+            && response.header.rescode == ResultCode::NOERROR
+        {
             accumulated_response.merge(response);
             return Ok(());
         }
 
-        if let Some(cname) = response.get_cname() {
-            let host = match cname {
-                DnsRecord::CNAME { host, .. } => host,
-                _ => unreachable!(),
-            };
+        if hops > 0 {
+            // The server may know that the name the chain ends at doesn't exist, or has no records
+            // of this type. Otherwise the rest of the chain is for the servers of the target
+            let negative = response.header.rescode == ResultCode::NXDOMAIN
+                || response
+                    .authorities
+                    .iter()
+                    .any(|record| matches!(record, DnsRecord::SOA { .. }));
 
-            if qtype == QueryType::CNAME {
+            accumulated_response.merge(response);
+            if negative {
                 return Ok(());
             }
 
-            accumulated_response.merge(response);
-            return recursive_lookup(
-                host.as_str(),
-                QueryType::A,
-                is_udp,
-                accumulated_response,
-                cache,
-            );
+            return recursive_lookup(&target, qtype, accumulated_response, ctx, resolution);
         }
 
         // If we get a NXDOMAIN reply, it means that the authoritative server is telling us the
@@ -821,48 +2823,288 @@ fn recursive_lookup(
             return Ok(());
         }
 
-        // If we find a new nameserver that has already been resolved by the last ns
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
-            continue;
+        // NODATA: the name exists, but has no records of the type we asked for
+        if response.negative_soa().is_some() {
+            accumulated_response.merge(response);
+            return Ok(());
         }
 
-        // Otherwise, resolve the ip of the NS record. If we don't find any,
-        // return what the last server sent us
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
+        // Anything but a referral further down is all the last server has to say
+        let cut = match response.get_referral(qname, &zone) {
+            Some(cut) => cut.to_string(),
             None => {
                 accumulated_response.merge(response);
                 return Ok(());
             }
         };
 
-        // Starting another lookup sequence to try and find an appropriate name server IP addr
-        let mut recursive_response = DnsPacket::new();
-        recursive_lookup(
-            &new_ns_name,
-            QueryType::A,
-            is_udp,
-            &mut recursive_response,
-            cache,
-        )?;
+        // If the referral came with glue, the new nameservers can be asked right away
+        let resolved = response.get_resolved_ns(&cut);
+        if !resolved.is_empty() {
+            resolution.follow_referral()?;
+            nameservers = resolved;
+            zone = cut;
+            continue;
+        }
 
-        // Pick a random ip from the result, and restart the loop. If no such record is available,
-        // return what the last server sent us
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            accumulated_response.merge(recursive_response);
-            return Ok(());
+        // Otherwise, resolve the NS names until one of them has an address
+        let unresolved: Vec<&str> = response.get_unresolved_ns(&cut).collect();
+
+        let mut addresses = Vec::new();
+        for new_ns_name in unresolved {
+            // Starting another lookup sequence to try and find an appropriate name server IP
+            // addr, of either family so that IPv6-only hosts have something to work with
+            for ns_qtype in [QueryType::A, QueryType::AAAA] {
+                let mut recursive_response = DnsPacket::new();
+                match recursive_lookup(
+                    new_ns_name,
+                    ns_qtype,
+                    &mut recursive_response,
+                    ctx,
+                    resolution,
+                ) {
+                    Ok(_) => addresses.extend(recursive_response.get_addresses()),
+                    Err(e) if e.is::<LimitExceeded>() => return Err(e),
+                    Err(e) => eprintln!("Failed to resolve nameserver {}: {}", new_ns_name, e),
+                }
+            }
+
+            if nameserver_addrs(&addresses, ctx).is_empty() {
+                addresses.clear();
+            } else {
+                break;
+            }
+        }
+
+        if addresses.is_empty() {
+            return Err(anyhow!(
+                "None of the nameservers for {} could be resolved",
+                qname
+            ));
+        }
+        resolution.follow_referral()?;
+        nameservers = addresses;
+        zone = cut;
+    }
+}
+
+/// The nameservers at `addresses` we have a source address to query from, fastest first.
+fn nameserver_addrs(addresses: &[IpAddr], ctx: &ServerContext) -> Vec<SocketAddr> {
+    let mut servers: Vec<SocketAddr> = addresses
+        .iter()
+        .map(|addr| SocketAddr::new(*addr, 53))
+        .filter(|server| ctx.config.can_reach(server))
+        .collect();
+    ctx.rtt.read().unwrap().sort(&mut servers);
+
+    servers
+}
+
+/// Brings copies of our zones up to date, then serves them. The zone store is only locked to
+/// swap each one in, so queries and transfers are answered from the old versions meanwhile.
+fn update_zones(pending: Vec<PendingZone>, ctx: &ServerContext) {
+    for mut zone in pending {
+        let result = zone.update(dnssec::now());
+        ctx.zones.write().unwrap().finish(zone, result);
+    }
+}
+
+/// Asks the root hints for the current set of root nameservers and their addresses (RFC 8109),
+/// and starts using those. Returns how long the new set may be used for.
+fn prime_root_servers(ctx: &ServerContext) -> Result<u32> {
+    let servers = nameserver_addrs(&ctx.config.root_hints, ctx);
+    prime_from(&servers, ctx)
+}
+
+/// Primes the root servers from `servers`. The question always goes out to them, as the cache
+/// only keeps the NS records of an answer and not the addresses that came with them.
+fn prime_from(servers: &[SocketAddr], ctx: &ServerContext) -> Result<u32> {
+    let response = query_servers(
+        "",
+        QueryType::NS,
+        servers,
+        "",
+        false,
+        ctx,
+        &mut ResolutionContext::new(),
+    )?;
+
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for record in &response.answers {
+        if let DnsRecord::NS {
+            domain,
+            host,
+            ttl: ns_ttl,
+            ..
+        } = record
+        {
+            if domain.is_empty() {
+                addresses.extend(response.get_glue(host));
+                ttl = ttl.min(*ns_ttl);
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        return Err(anyhow!("The priming response had no root server addresses"));
+    }
+
+    println!("Primed {} root server addresses", addresses.len());
+    *ctx.root_servers.write().unwrap() = addresses;
+
+    Ok(ttl)
+}
+
+/// Asks `servers` in order until one of them gives a usable answer. A server that doesn't answer
+/// in time, or answers with SERVFAIL or REFUSED, is skipped, and the whole list is gone through
+/// again up to the configured number of retries before giving up. With `cached`, answers the
+/// cache already has are taken from there instead.
+fn query_servers(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[SocketAddr],
+    zone: &str,
+    cached: bool,
+    ctx: &ServerContext,
+    resolution: &mut ResolutionContext,
+) -> Result<DnsPacket> {
+    let mut last_error = anyhow!("No servers to ask about {}", qname);
+
+    for _ in 0..=ctx.config.retries {
+        for server in servers {
+            let result = if cached {
+                lookup(qname, qtype, *server, zone, ctx, resolution)
+            } else {
+                query_server(qname, qtype, *server, zone, ctx, resolution)
+            };
+            match result {
+                Ok(response)
+                    if matches!(
+                        response.header.rescode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED
+                    ) =>
+                {
+                    last_error = anyhow!(
+                        "{} answered {:?} for {}",
+                        server,
+                        response.header.rescode,
+                        qname
+                    );
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if e.is::<LimitExceeded>() => return Err(e),
+                Err(e) => last_error = anyhow!("{} failed to resolve {}: {}", server, qname, e),
+            }
+            eprintln!("{}", last_error);
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Answers a question about a name outside of our zones, either by forwarding it or by
+/// recursing from the root, the way the configuration says to.
+fn resolve(
+    qname: &str,
+    qtype: QueryType,
+    packet: &mut DnsPacket,
+    ctx: &ServerContext,
+    resolution: &mut ResolutionContext,
+) -> Result<()> {
+    match ctx.config.forwarders_for(qname) {
+        Some(servers) => {
+            // Forwarders are resolvers themselves, so they get to speak for any name
+            let response = query_servers(qname, qtype, servers, "", true, ctx, resolution)?;
+            packet.merge(response);
+            Ok(())
+        }
+        None => recursive_lookup(qname, qtype, packet, ctx, resolution),
+    }
+}
+
+/// Resolves a question for a client, and checks the answer's signatures unless validation is
+/// turned off or the client asked to do that itself. Returns whether the answer is secure.
+fn resolve_validated(
+    qname: &str,
+    qtype: QueryType,
+    packet: &mut DnsPacket,
+    checking_disabled: bool,
+    ctx: &ServerContext,
+) -> Result<bool> {
+    let mut resolution = ResolutionContext::new();
+    resolve(qname, qtype, packet, ctx, &mut resolution)?;
+
+    if !ctx.config.dnssec_validation || checking_disabled {
+        return Ok(false);
+    }
+
+    let security = validation::validate(qname, qtype, packet, ctx, &mut resolution)?;
+    Ok(security == Security::Secure)
+}
+
+/// Writes the records of a zone transfer across as many messages as they need. Only the first
+/// one repeats the question (RFC 5936 section 2.2).
+fn write_transfer(mut packet: DnsPacket, records: &[DnsRecord]) -> Result<Vec<BytePacketWriter>> {
+    let mut messages = Vec::new();
+    let mut rest = records;
+
+    loop {
+        packet.answers = rest[..rest.len().min(MAX_TRANSFER_RECORDS)].to_vec();
+        let mut buffer = BytePacketWriter::new();
+        packet.write(&mut buffer, MAX_PACKET_LEN)?;
+
+        // Records that didn't fit go in the next message, this one mustn't look truncated. An
+        // RRset too large for a message of its own is split over several (RFC 5936 section 2.2)
+        while packet.header.truncated_message {
+            let fits = match packet.header.answers as usize {
+                0 => packet.answers.len() / 2,
+                fits => fits,
+            };
+            packet.answers.truncate(fits);
+            if packet.answers.is_empty() {
+                return Err(anyhow!("{} is too large to transfer", rest[0].domain()));
+            }
+            buffer = BytePacketWriter::new();
+            packet.write(&mut buffer, MAX_PACKET_LEN)?;
+        }
+        messages.push(buffer);
+
+        rest = &rest[packet.answers.len()..];
+        if rest.is_empty() {
+            return Ok(messages);
         }
+        packet.questions.clear();
+        packet.edns = None;
     }
 }
 
+/// The Extended DNS Error option telling EDNS clients why resolution failed with `error`
+/// (RFC 8914): which limit stopped it, as "Other", or why the answer failed validation, as
+/// "DNSSEC Bogus".
+fn extended_error(error: &anyhow::Error) -> Option<EdnsOption> {
+    let (info_code, text) = match (error.downcast_ref(), error.downcast_ref()) {
+        (Some(LimitExceeded(limit)), _) => (0u16, limit.to_string()),
+        (_, Some(Bogus(reason))) => (6, reason.clone()),
+        _ => return None,
+    };
+
+    let mut data = info_code.to_be_bytes().to_vec();
+    data.extend_from_slice(text.as_bytes());
+    Some(EdnsOption {
+        code: EDNS_OPTION_EDE,
+        data,
+    })
+}
+
+/// Answers the query in `req_buffer` from `client`. That takes a single response, except for
+/// zone transfers over TCP.
 fn handle_query(
-    req_buffer: &mut BytePacketBuffer,
+    req_buffer: &mut BytePacketReader,
+    client: IpAddr,
     is_udp: bool,
-    cache: &SharedDnsCache,
-) -> Result<BytePacketBuffer> {
+    ctx: &ServerContext,
+) -> Result<Vec<BytePacketWriter>> {
     let mut request = DnsPacket::from_buffer(req_buffer)?;
 
     let mut packet = DnsPacket::new();
@@ -870,17 +3112,102 @@ fn handle_query(
     packet.header.recursion_desired = true;
     packet.header.recursion_available = true;
     packet.header.response = true;
+    packet.header.checking_disabled = request.header.checking_disabled;
+    packet.header.opcode = request.header.opcode;
+
+    // Only answer with an OPT record if the client sent one
+    let max_size = match &request.edns {
+        Some(edns) => {
+            packet.edns = Some(Edns::new(EDNS_UDP_PAYLOAD_SIZE));
+            edns.max_payload()
+        }
+        None => DEFAULT_UDP_PAYLOAD_SIZE,
+    };
 
     match request.questions.pop() {
+        Some(question) if request.header.opcode == OPCODE_NOTIFY => {
+            packet.header.rescode = ctx.secondaries.notify(&question.name, client);
+            packet.header.authoritative_answer = true;
+            packet.questions.push(question);
+        }
+        Some(question) if request.header.opcode != OPCODE_QUERY => {
+            packet.header.rescode = ResultCode::NOTIMP;
+            packet.questions.push(question);
+        }
+        // We only speak EDNS version 0, anything newer gets BADVERS
+        Some(question) if request.edns.as_ref().is_some_and(|edns| edns.version > 0) => {
+            if let Some(edns) = packet.edns.as_mut() {
+                edns.extended_rcode = 1;
+            }
+            packet.questions.push(question);
+        }
+        Some(question) if matches!(question.qtype, QueryType::AXFR | QueryType::IXFR) => {
+            println!("Received query: {:?}", question);
+            let records = transfer::answer(&question, &request, client, is_udp, ctx, &mut packet);
+            packet.questions.push(question);
+
+            return write_transfer(packet, &records);
+        }
         Some(question) => {
             println!("Received query: {:?}", question);
+            let dnssec_ok = request.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+            if let Some(edns) = packet.edns.as_mut() {
+                edns.dnssec_ok = dnssec_ok;
+            }
 
-            match recursive_lookup(&question.name, question.qtype, is_udp, &mut packet, &cache) {
-                Ok(_) => {
-                    packet.questions.push(question);
+            // Names in our own zones are answered from there, everything else is resolved
+            let answered = match ctx
+                .zones
+                .read()
+                .unwrap()
+                .find(&question.name)
+                .filter(|zone| zone.class() == question.qclass)
+            {
+                Some(zone) => {
+                    zone.answer(&question.name, question.qtype, dnssec_ok, &mut packet);
+                    true
                 }
-                Err(_) => {
-                    packet.header.rescode = ResultCode::SERVFAIL;
+                None => false,
+            };
+
+            if answered {
+                packet.questions.push(question);
+            } else if question.qclass == QueryClass::CH {
+                chaos::answer(&question.name, question.qtype, &ctx.config, &mut packet);
+                packet.questions.push(question);
+            } else if question.qclass != QueryClass::IN {
+                // There is nobody to recurse to for the other classes
+                packet.header.rescode = ResultCode::REFUSED;
+                packet.questions.push(question);
+            } else {
+                match resolve_validated(
+                    &question.name,
+                    question.qtype,
+                    &mut packet,
+                    request.header.checking_disabled,
+                    ctx,
+                ) {
+                    Ok(secure) => {
+                        // AD is only for clients that show they understand it (RFC 6840)
+                        packet.header.authed_data =
+                            secure && (dnssec_ok || request.header.authed_data);
+                        if !dnssec_ok {
+                            packet.strip_dnssec(question.qtype);
+                        }
+                        packet.questions.push(question);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to resolve {}: {}", question.name, e);
+                        packet.header.rescode = ResultCode::SERVFAIL;
+                        packet.answers.clear();
+                        packet.authorities.clear();
+
+                        if let (Some(option), Some(edns)) =
+                            (extended_error(&e), packet.edns.as_mut())
+                        {
+                            edns.options.push(option);
+                        }
+                    }
                 }
             }
         }
@@ -889,64 +3216,1243 @@ fn handle_query(
         }
     }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    packet.write(&mut res_buffer, is_udp)?;
+    let mut res_buffer = BytePacketWriter::new();
+    packet.write(
+        &mut res_buffer,
+        if is_udp { max_size } else { MAX_PACKET_LEN },
+    )?;
 
-    Ok(res_buffer)
+    Ok(vec![res_buffer])
 }
 
-fn handle_tcp_query(stream: &mut TcpStream, cache: &SharedDnsCache) -> Result<()> {
-    let mut req_buffer = BytePacketBuffer::new();
-    let mut req_size_buf = [0u8; 2];
-    stream.read_exact(&mut req_size_buf)?;
-    stream.read(&mut req_buffer.buf)?;
-
-    let res_buffer = handle_query(&mut req_buffer, false, cache)?;
-    let len = res_buffer.pos;
+/// Answers queries on a TCP connection until the client closes it, or leaves it idle for too
+/// long (RFC 7766 section 6.2.1).
+fn handle_tcp_query(stream: &mut TcpStream, ctx: &ServerContext) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let client = stream.peer_addr()?.ip();
 
-    stream.write(&[(len >> 8) as u8, (len & 0xFF) as u8])?;
-    stream.write(&res_buffer.buf[0..len])?;
-    stream.flush()?;
+    while let Ok(req) = read_tcp_message(stream) {
+        for message in handle_query(&mut BytePacketReader::new(&req), client, false, ctx)? {
+            write_tcp_message(stream, &message)?;
+        }
+    }
 
     Ok(())
 }
 
-fn handle_udp_query(socket: &UdpSocket, cache: &SharedDnsCache) -> Result<()> {
-    let mut req_buffer = BytePacketBuffer::new();
-
-    let (_, src) = socket.recv_from(&mut req_buffer.buf)?;
+fn handle_udp_query(socket: &UdpSocket, ctx: &ServerContext) -> Result<()> {
+    let mut req = [0; BUF_LEN];
 
-    let res_buffer = handle_query(&mut req_buffer, true, cache)?;
-    let len = res_buffer.pos;
+    let (len, src) = socket.recv_from(&mut req)?;
 
-    socket.send_to(&res_buffer.buf[0..len], src)?;
+    for message in handle_query(&mut BytePacketReader::new(&req[..len]), src.ip(), true, ctx)? {
+        socket.send_to(message.as_bytes(), src)?;
+    }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let socket = UdpSocket::bind(("0.0.0.0", 2053))?;
-    let tcp_socket = TcpListener::bind(("0.0.0.0", 2053))?;
+/// Binds a listening socket. IPv6 sockets only take IPv6 traffic, so that they can share a
+/// port with an IPv4 socket on the same host.
+fn bind_listener(addr: SocketAddr, kind: Type) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    Ok(socket)
+}
 
-    let cache = Arc::new(RwLock::new(DnsCache::new(1000)));
+fn serve_udp(addr: SocketAddr, ctx: Arc<ServerContext>) -> Result<thread::JoinHandle<()>> {
+    let socket: UdpSocket = bind_listener(addr, Type::DGRAM)?.into();
 
-    let udp_cache = cache.clone();
-    thread::spawn(move || loop {
-        match handle_udp_query(&socket, &udp_cache) {
+    Ok(thread::spawn(move || loop {
+        match handle_udp_query(&socket, &ctx) {
             Ok(_) => {}
             Err(e) => eprintln!("An error ocurred: {}", e),
         }
-    });
+    }))
+}
 
-    for stream in tcp_socket.incoming() {
-        let cache = cache.clone();
-        match stream {
-            Ok(mut stream) => {
-                thread::spawn(move || handle_tcp_query(&mut stream, &cache));
+fn serve_tcp(addr: SocketAddr, ctx: Arc<ServerContext>) -> Result<thread::JoinHandle<()>> {
+    let socket = bind_listener(addr, Type::STREAM)?;
+    socket.listen(128)?;
+    let listener: TcpListener = socket.into();
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let ctx = ctx.clone();
+            match stream {
+                Ok(mut stream) => {
+                    thread::spawn(move || handle_tcp_query(&mut stream, &ctx));
+                }
+                Err(e) => eprintln!("An error ocurred: {}", e),
             }
-            Err(e) => eprintln!("An error ocurred: {}", e),
         }
+    }))
+}
+
+fn main() -> Result<()> {
+    let config = Config::from_args(std::env::args().skip(1))?;
+
+    let zones = ZoneStore::load(&config.zones, &config.zone_keys)?;
+    let ctx = Arc::new(ServerContext {
+        cache: RwLock::new(DnsCache::new(config.cache_size)),
+        rtt: RwLock::new(RttTable::new()),
+        root_servers: RwLock::new(config.root_hints.clone()),
+        secondaries: Secondaries::new(&config.secondary_zones, &zones)?,
+        zones: RwLock::new(zones),
+        config,
+    });
+
+    // Keep our copies of secondary zones in step with their primaries
+    if !ctx.secondaries.is_empty() {
+        let ctx = ctx.clone();
+        thread::spawn(move || ctx.secondaries.run(&ctx));
+    }
+
+    // Keep the root server set fresh, priming again whenever the last answer expires
+    if ctx.config.mode == ResolverMode::Recursive {
+        let ctx = ctx.clone();
+        thread::spawn(move || loop {
+            let wait = match prime_root_servers(&ctx) {
+                Ok(ttl) => Duration::from_secs(ttl.into()).max(PRIME_RETRY_INTERVAL),
+                Err(e) => {
+                    eprintln!("Priming the root servers failed: {}", e);
+                    PRIME_RETRY_INTERVAL
+                }
+            };
+            thread::sleep(wait);
+        });
+    }
+
+    // Pick up edited zone files, and sign our zones again before their signatures run out
+    if !ctx.config.zones.is_empty() {
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let mut last_signed = Instant::now();
+            loop {
+                thread::sleep(ZONE_CHECK_INTERVAL);
+                let changed = ctx.zones.read().unwrap().changed_files();
+                update_zones(changed, &ctx);
+
+                if last_signed.elapsed() >= RESIGN_INTERVAL {
+                    last_signed = Instant::now();
+                    let signed = ctx.zones.read().unwrap().signed_zones();
+                    update_zones(signed, &ctx);
+                }
+            }
+        });
+    }
+
+    let mut listeners = Vec::new();
+    for addr in &ctx.config.listen {
+        listeners.push(serve_udp(*addr, ctx.clone())?);
+        listeners.push(serve_tcp(*addr, ctx.clone())?);
+        println!("Listening on {}", addr);
+    }
+
+    for listener in listeners {
+        let _ = listener.join();
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A context with the default configuration and nothing cached or loaded yet.
+    pub(crate) fn context() -> ServerContext {
+        ServerContext {
+            config: Default::default(),
+            cache: RwLock::new(DnsCache::new(10)),
+            rtt: RwLock::new(RttTable::new()),
+            zones: RwLock::new(ZoneStore::new()),
+            secondaries: Secondaries::new(&HashMap::new(), &ZoneStore::new()).unwrap(),
+            root_servers: RwLock::new(Vec::new()),
+        }
+    }
+
+    fn round_trip(record: DnsRecord) {
+        let mut writer = BytePacketWriter::new();
+        let written = record.write(&mut writer).unwrap();
+        assert_eq!(written, writer.pos());
+
+        let mut reader = BytePacketReader::new(writer.as_bytes());
+        assert_eq!(DnsRecord::read(&mut reader).unwrap(), record);
+        assert_eq!(reader.pos(), writer.pos());
+    }
+
+    /// Parses a captured message and checks that writing it back gives the very same bytes.
+    fn rewrite_capture(capture: &[u8]) -> DnsPacket {
+        let packet = DnsPacket::from_buffer(&mut BytePacketReader::new(capture)).unwrap();
+
+        let mut writer = BytePacketWriter::new();
+        packet.clone().write(&mut writer, MAX_PACKET_LEN).unwrap();
+        assert_eq!(writer.as_bytes(), capture);
+
+        packet
+    }
+
+    #[test]
+    fn writer_encodes_integers_big_endian() {
+        let mut writer = BytePacketWriter::new();
+        writer.write_u16(0xABCD).unwrap();
+        writer.write_u32(0x1234_5678).unwrap();
+        writer.write_u16(0).unwrap();
+        writer.set_u16(6, 0x0102).unwrap();
+
+        assert_eq!(
+            writer.as_bytes(),
+            &[0xAB, 0xCD, 0x12, 0x34, 0x56, 0x78, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = DnsHeader {
+            id: 0xBEEF,
+            recursion_desired: true,
+            truncated_message: true,
+            authoritative_answer: true,
+            opcode: 2,
+            response: true,
+            rescode: ResultCode::NXDOMAIN,
+            checking_disabled: true,
+            authed_data: true,
+            z: false,
+            recursion_available: true,
+            questions: 1,
+            answers: 2,
+            authoritative_entries: 3,
+            resource_entries: 4,
+        };
+
+        let mut writer = BytePacketWriter::new();
+        header.write(&mut writer).unwrap();
+        assert_eq!(
+            writer.as_bytes(),
+            &[0xBE, 0xEF, 0x97, 0xB3, 0, 1, 0, 2, 0, 3, 0, 4]
+        );
+
+        let mut read = DnsHeader::new();
+        read.read(&mut BytePacketReader::new(writer.as_bytes()))
+            .unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", header));
+    }
+
+    #[test]
+    fn record_data_must_match_its_length() {
+        // Two A records for the root, the first with the given RDLENGTH and that much data
+        let message = |data_len: u8| {
+            let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 0, 0, 2, 0, 0, 0, 0];
+            message.extend([0, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, data_len]);
+            message.extend((0..data_len).map(|i| 192 + i));
+            message.extend([0, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 192, 0, 2, 1]);
+            message
+        };
+        let parse = |message: &[u8]| DnsPacket::from_buffer(&mut BytePacketReader::new(message));
+
+        let packet = parse(&message(4)).unwrap();
+        assert_eq!(packet.answers.len(), 2);
+
+        // Neither the rest of an AAAA address nor a record cut short is read as the next record
+        assert!(parse(&message(16)).is_err());
+        assert!(parse(&message(2)).is_err());
+    }
+
+    #[test]
+    fn a_round_trip() {
+        round_trip(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: "93.184.216.34".parse().unwrap(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn aaaa_round_trip() {
+        round_trip(DnsRecord::AAAA {
+            domain: "example.com".to_string(),
+            addr: "2606:2800:220:1:248:1893:25c8:1946".parse().unwrap(),
+            class: QueryClass::IN,
+            ttl: 86400,
+        });
+    }
+
+    #[test]
+    fn ns_round_trip() {
+        round_trip(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "a.iana-servers.net".to_string(),
+            class: QueryClass::IN,
+            ttl: 172800,
+        });
+    }
+
+    #[test]
+    fn cname_round_trip() {
+        round_trip(DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            host: "example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 300,
+        });
+    }
+
+    #[test]
+    fn soa_round_trip() {
+        round_trip(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            mname: "ns.icann.org".to_string(),
+            rname: "noc.dns.icann.org".to_string(),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 3600,
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn mx_round_trip() {
+        round_trip(DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 65536,
+        });
+    }
+
+    #[test]
+    fn ptr_round_trip() {
+        round_trip(DnsRecord::PTR {
+            domain: "34.216.184.93.in-addr.arpa".to_string(),
+            host: "example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn txt_round_trip() {
+        round_trip(DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            strings: vec![b"v=spf1 -all".to_vec(), Vec::new(), vec![0, 0xFF, b'"']],
+            class: QueryClass::IN,
+            ttl: 300,
+        });
+    }
+
+    #[test]
+    fn srv_round_trip() {
+        round_trip(DnsRecord::SRV {
+            domain: "_sip._tcp.example.com".to_string(),
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            host: "sip.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn srv_target_is_not_compressed() {
+        let record = DnsRecord::SRV {
+            domain: "_sip._tcp.example.com".to_string(),
+            priority: 0,
+            weight: 0,
+            port: 5060,
+            host: "example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 60,
+        };
+
+        let mut writer = BytePacketWriter::new();
+        record.write(&mut writer).unwrap();
+        assert!(writer.as_bytes().ends_with(b"\x07example\x03com\x00"));
+    }
+
+    #[test]
+    fn caa_round_trip() {
+        round_trip(DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 128,
+            tag: "issue".to_string(),
+            value: b"letsencrypt.org".to_vec(),
+            class: QueryClass::IN,
+            ttl: 86400,
+        });
+    }
+
+    #[test]
+    fn ds_round_trip() {
+        round_trip(DnsRecord::DS {
+            domain: "example.com".to_string(),
+            key_tag: 60485,
+            algorithm: 5,
+            digest_type: 1,
+            digest: vec![0x2B; 20],
+            class: QueryClass::IN,
+            ttl: 86400,
+        });
+    }
+
+    #[test]
+    fn rrsig_round_trip() {
+        round_trip(DnsRecord::RRSIG {
+            domain: "host.example.com".to_string(),
+            type_covered: QueryType::A,
+            algorithm: 13,
+            labels: 3,
+            original_ttl: 86400,
+            expiration: 1048354263,
+            inception: 1045762263,
+            key_tag: 2642,
+            signer_name: "example.com".to_string(),
+            signature: vec![0xA0; 64],
+            class: QueryClass::IN,
+            ttl: 86400,
+        });
+    }
+
+    #[test]
+    fn nsec_round_trip() {
+        round_trip(DnsRecord::NSEC {
+            domain: "alfa.example.com".to_string(),
+            next_domain: "host.example.com".to_string(),
+            types: vec![
+                QueryType::A,
+                QueryType::MX,
+                QueryType::RRSIG,
+                QueryType::CAA,
+            ],
+            class: QueryClass::IN,
+            ttl: 86400,
+        });
+    }
+
+    #[test]
+    fn nsec_next_names_keep_their_case() {
+        let nsec = DnsRecord::NSEC {
+            domain: "alfa.example.com".to_string(),
+            next_domain: "Host.Example.com".to_string(),
+            types: vec![QueryType::A, QueryType::RRSIG, QueryType::NSEC],
+            class: QueryClass::IN,
+            ttl: 86400,
+        };
+        round_trip(nsec.clone());
+
+        // Owner names are lowercased in canonical form, the next name stays as it was sent
+        let mut upper = nsec.clone();
+        upper.set_domain("ALFA.example.com".to_string());
+        let canonical = upper.to_canonical(86400).unwrap();
+        assert_eq!(canonical, nsec.to_canonical(86400).unwrap());
+        assert!(canonical.starts_with(b"\x04alfa\x07example\x03com\x00"));
+        assert!(canonical
+            .windows(18)
+            .any(|window| window == b"\x04Host\x07Example\x03com\x00"));
+    }
+
+    #[test]
+    fn dnskey_round_trip() {
+        round_trip(DnsRecord::DNSKEY {
+            domain: "example.com".to_string(),
+            flags: 257,
+            protocol: 3,
+            algorithm: 15,
+            public_key: (0..32).collect(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+    }
+
+    #[test]
+    fn nsec3_round_trip() {
+        round_trip(DnsRecord::NSEC3 {
+            domain: "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example".to_string(),
+            hash_algorithm: 1,
+            flags: 1,
+            iterations: 12,
+            salt: vec![0xAA, 0xBB, 0xCC, 0xDD],
+            next_hashed: (0..20).collect(),
+            types: vec![QueryType::NS, QueryType::SOA, QueryType::NSEC3PARAM],
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+        round_trip(DnsRecord::NSEC3PARAM {
+            domain: "example".to_string(),
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 0,
+            salt: Vec::new(),
+            class: QueryClass::IN,
+            ttl: 0,
+        });
+    }
+
+    #[test]
+    fn nsec_type_bitmap_capture() {
+        // The example from RFC 4034 section 4.3, which includes TYPE1234 in a second window
+        let capture = [
+            &b"\x04alfa\x07example\x03com\x00\x00\x2f\x00\x01\x00\x01\x51\x80\x00\x37"[..],
+            b"\x04host\x07example\x03com\x00",
+            b"\x00\x06\x40\x01\x00\x00\x00\x03",
+            b"\x04\x1b\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+            b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x20",
+        ]
+        .concat();
+
+        let mut reader = BytePacketReader::new(&capture);
+        let record = DnsRecord::read(&mut reader).unwrap();
+        assert_eq!(reader.pos(), capture.len());
+        assert_eq!(
+            record.to_string(),
+            "alfa.example.com. 86400 IN NSEC host.example.com. A MX RRSIG NSEC TYPE1234"
+        );
+
+        let mut writer = BytePacketWriter::without_compression();
+        record.write(&mut writer).unwrap();
+        assert_eq!(writer.as_bytes(), &capture[..]);
+    }
+
+    #[test]
+    fn dnssec_records_in_master_files() {
+        // Examples from RFC 4034 and RFC 5155
+        let text = "$ORIGIN example.com.\n\
+            dskey 86400 IN DNSKEY 256 3 5 ( AQOeiiR0GOMYkDshWoSKz9Xz\n\
+                fwJr1AYtsmx3TGkJaNXVbfi/ 2pHm822aJ5iI9BMzNXxeYCmZ\n\
+                DRD99WYwYqUSdjMmmAphXdvx egXd/M5+X7OrzKBaMbCVdFLU\n\
+                Uh6DhweJBjEVv5f2wwjM9Xzc nOf+EPbtG9DMBmADjFDc2w/r\n\
+                ljwvFw== ) ; key id = 60485\n\
+            dskey 86400 IN DS 60485 5 1 ( 2BB183AF5F22588179A53B0A\n\
+                98631FAD1A292118 )\n\
+            host 86400 IN RRSIG A 5 3 86400 20030322173103 ( 20030220173103 2642\n\
+                example.com. oJB1W6WNGv+ldvQ3WDG0MQkg5IEhjRip8WTrPYGv07h108dUKGMeDPKijVCHX3DDKdfb+v6o\n\
+                B9wfuh3DTJXUAfI/M0zmO/zz8bW0Rznl8O3tGNazPwQKkRN20XPXV6nwwfoXmJQbsLNrLfkG\n\
+                J5D6fwFm8nN+6pBzeDQfsS3Ap3o= )\n\
+            $ORIGIN example.\n\
+            0p9mhaveqvm6t7vbl5lop2u3t2rp3tom 3600 IN NSEC3 1 1 12 aabbccdd (\n\
+                2t7b4g4vsa5smi47k61mv5bv1a22bojr MX DNSKEY NS SOA NSEC3PARAM RRSIG )\n\
+            @ 3600 IN NSEC3PARAM 1 0 12 aabbccdd\n";
+        let records = zone::parse_master_file(text, None).unwrap();
+
+        let lines: Vec<String> = records.iter().map(DnsRecord::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "dskey.example.com. 86400 IN DNSKEY 256 3 5 AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGk\
+                 JaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZDRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFL\
+                 UUh6DhweJBjEVv5f2wwjM9XzcnOf+EPbtG9DMBmADjFDc2w/rljwvFw==",
+                "dskey.example.com. 86400 IN DS 60485 5 1 2BB183AF5F22588179A53B0A98631FAD1A292118",
+                "host.example.com. 86400 IN RRSIG A 5 3 86400 20030322173103 20030220173103 2642 \
+                 example.com. oJB1W6WNGv+ldvQ3WDG0MQkg5IEhjRip8WTrPYGv07h108dUKGMeDPKijVCHX3DDKdfb+\
+                 v6oB9wfuh3DTJXUAfI/M0zmO/zz8bW0Rznl8O3tGNazPwQKkRN20XPXV6nwwfoXmJQbsLNrLfkGJ5D6fw\
+                 Fm8nN+6pBzeDQfsS3Ap3o=",
+                "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example. 3600 IN NSEC3 1 1 12 AABBCCDD \
+                 2T7B4G4VSA5SMI47K61MV5BV1A22BOJR NS SOA MX RRSIG DNSKEY NSEC3PARAM",
+                "example. 3600 IN NSEC3PARAM 1 0 12 AABBCCDD",
+            ]
+        );
+
+        // What is displayed parses back into the same records
+        let reparsed = zone::parse_master_file(&lines.join("\n"), None).unwrap();
+        assert_eq!(reparsed, records);
+    }
+
+    #[test]
+    fn rrsets_in_canonical_order() {
+        let record = |addr: [u8; 4], ttl: u32| DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: addr.into(),
+            class: QueryClass::IN,
+            ttl,
+        };
+
+        // Canonical order goes by RDATA, and the original TTL replaces whatever is left
+        let rrset = [
+            record([192, 0, 2, 2], 10),
+            record([10, 0, 0, 1], 20),
+            record([192, 0, 2, 2], 30),
+        ];
+        let expected = [
+            record([10, 0, 0, 1], 3600).to_canonical(3600).unwrap(),
+            record([192, 0, 2, 2], 3600).to_canonical(3600).unwrap(),
+        ]
+        .concat();
+        assert_eq!(DnsRecord::canonical_rrset(&rrset, 3600).unwrap(), expected);
+
+        // Names are spelled out in full, even where a pointer could have been used
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 0,
+        };
+        assert!(ns
+            .to_canonical(0)
+            .unwrap()
+            .ends_with(b"\x02ns\x07example\x03com\x00"));
+    }
+
+    #[test]
+    fn dnssec_records_are_stripped_unless_asked_for() {
+        let text = "$ORIGIN example.com.\n\
+            www 300 IN A 192.0.2.1\n\
+            www 300 IN RRSIG A 13 3 300 20300101000000 20240101000000 1234 example.com. AAAA\n\
+            www 300 IN NSEC zz A RRSIG NSEC\n";
+        let records = zone::parse_master_file(text, None).unwrap();
+
+        let mut packet = DnsPacket::new();
+        packet.answers = records.clone();
+        packet.authorities = records[1..].to_vec();
+        packet.strip_dnssec(QueryType::A);
+        assert_eq!(packet.answers, records[..1]);
+        assert!(packet.authorities.is_empty());
+
+        // Unless they are what the question was about
+        let mut packet = DnsPacket::new();
+        packet.answers = records.clone();
+        packet.strip_dnssec(QueryType::NSEC);
+        assert_eq!(packet.answers, [records[0].clone(), records[2].clone()]);
+
+        // Signatures are cached along with the RRset they cover
+        let mut cache = DnsCache::new(10);
+        cache.insert_records(&records[..2], &records[2..]);
+        match cache.get("www.example.com", QueryType::A, QueryClass::IN) {
+            Some(CacheEntry::Records {
+                records: cached,
+                proof,
+            }) => {
+                let types: Vec<QueryType> = cached.iter().map(DnsRecord::qtype).collect();
+                assert_eq!(types, [QueryType::A, QueryType::RRSIG]);
+                assert!(proof.is_empty());
+            }
+            other => panic!("unexpected cache entry {:?}", other),
+        }
+
+        // Records expanded from a wildcard keep the proof that came with them
+        let mut expanded = records[..2].to_vec();
+        if let DnsRecord::RRSIG { labels, .. } = &mut expanded[1] {
+            *labels = 2;
+        }
+        cache.insert_records(&expanded, &records[2..]);
+        match cache.get("www.example.com", QueryType::A, QueryClass::IN) {
+            Some(CacheEntry::Records { proof, .. }) => {
+                assert_eq!(proof.len(), 1);
+                assert_eq!(proof[0].qtype(), QueryType::NSEC);
+            }
+            other => panic!("unexpected cache entry {:?}", other),
+        }
+    }
+
+    /// Makes a cached entry expire `secs` seconds from now, as if it had been in the cache for
+    /// a while.
+    fn expire_in(cache: &mut DnsCache, name: &str, qtype: QueryType, secs: u64) {
+        let key = RRsetKey::new(name, qtype, QueryClass::IN);
+        cache.entries.get_mut(&key).unwrap().expires = Instant::now() + Duration::from_secs(secs);
+    }
+
+    fn cached_ttls(entry: Option<CacheEntry>) -> Vec<u32> {
+        match entry {
+            Some(CacheEntry::Records { records, .. }) => {
+                records.iter().map(DnsRecord::ttl).collect()
+            }
+            Some(CacheEntry::Negative(negative)) => vec![negative.soa.ttl()],
+            None => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn cached_rrsets_count_down_and_expire() {
+        let text = "$ORIGIN example.com.\n\
+            www 300 IN A 192.0.2.1\n\
+            www 60 IN A 192.0.2.2\n\
+            www 300 IN AAAA 2001:db8::1\n\
+            www 300 CH TXT \"chaos\"\n\
+            mail 300 IN A 192.0.2.3\n";
+        let records = zone::parse_master_file(text, None).unwrap();
+        let mut cache = DnsCache::new(10);
+        cache.insert_records(&records, &[]);
+
+        // Each RRset is cached on its own, and lives as long as its shortest TTL
+        let ttls = cached_ttls(cache.get("www.example.com", QueryType::A, QueryClass::IN));
+        assert_eq!(ttls.len(), 2);
+        assert!(ttls.iter().all(|ttl| (59..=60).contains(ttl)));
+        assert_eq!(
+            cached_ttls(cache.get("www.example.com", QueryType::AAAA, QueryClass::IN)).len(),
+            1
+        );
+        assert_eq!(
+            cached_ttls(cache.get("www.example.com", QueryType::TXT, QueryClass::CH)).len(),
+            1
+        );
+        assert!(cache
+            .get("www.example.com", QueryType::TXT, QueryClass::IN)
+            .is_none());
+        assert!(cache
+            .get("ftp.example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+
+        // The TTLs handed out are what is left of them
+        expire_in(&mut cache, "mail.example.com", QueryType::A, 100);
+        assert!(matches!(
+            cached_ttls(cache.get("mail.example.com", QueryType::A, QueryClass::IN))[..],
+            [99 | 100]
+        ));
+
+        expire_in(&mut cache, "mail.example.com", QueryType::A, 0);
+        assert!(cache
+            .get("mail.example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+        assert!(cache
+            .get("www.example.com", QueryType::AAAA, QueryClass::IN)
+            .is_some());
+    }
+
+    #[test]
+    fn negative_answers_are_cached_for_the_soa_minimum() {
+        let soa = |ttl: u32, minimum: u32| DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            mname: "ns1.example.com".to_string(),
+            rname: "admin.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+            class: QueryClass::IN,
+            ttl,
+        };
+        let negative = |rescode: ResultCode, soa: DnsRecord| NegativeAnswer {
+            rescode,
+            soa,
+            proof: Vec::new(),
+        };
+
+        let mut cache = DnsCache::new(10);
+        cache.insert_negative(
+            "nx.example.com",
+            QueryType::A,
+            negative(ResultCode::NXDOMAIN, soa(3600, 300)),
+        );
+        cache.insert_negative(
+            "www.example.com",
+            QueryType::AAAA,
+            negative(ResultCode::NOERROR, soa(60, 300)),
+        );
+
+        // Whichever of the SOA's TTL and minimum is lower (RFC 2308 section 5)
+        match cache.get("nx.example.com", QueryType::A, QueryClass::IN) {
+            Some(CacheEntry::Negative(negative)) => {
+                assert_eq!(negative.rescode, ResultCode::NXDOMAIN);
+                assert!((299..=300).contains(&negative.soa.ttl()));
+            }
+            other => panic!("unexpected cache entry {:?}", other),
+        }
+        assert!(matches!(
+            cached_ttls(cache.get("www.example.com", QueryType::AAAA, QueryClass::IN))[..],
+            [59 | 60]
+        ));
+        assert!(cache
+            .get("www.example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+
+        expire_in(&mut cache, "nx.example.com", QueryType::A, 0);
+        assert!(cache
+            .get("nx.example.com", QueryType::A, QueryClass::IN)
+            .is_none());
+    }
+
+    #[test]
+    fn udp_responses_are_trimmed_to_whole_rrsets() {
+        let txt = |domain: &str, i: u8| DnsRecord::TXT {
+            domain: domain.to_string(),
+            strings: vec![vec![b'0' + i; 60]],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new(
+            "a.example.com".to_string(),
+            QueryType::TXT,
+            QueryClass::IN,
+        ));
+        packet.answers = (0..4).map(|i| txt("a.example.com", i)).collect();
+        packet
+            .answers
+            .extend((0..4).map(|i| txt("b.example.com", i)));
+        packet.edns = Some(Edns::new(512));
+        let written = |packet: &mut DnsPacket, max_size: usize| {
+            let mut buffer = BytePacketWriter::new();
+            packet.write(&mut buffer, max_size).unwrap();
+            assert!(buffer.pos() <= max_size);
+            DnsPacket::from_buffer(&mut BytePacketReader::new(buffer.as_bytes())).unwrap()
+        };
+
+        // The second RRset only partly fits, so none of it is sent
+        let response = written(&mut packet.clone(), 512);
+        assert!(response.header.truncated_message);
+        assert_eq!(response.answers, packet.answers[..4]);
+        assert_eq!(response.edns.unwrap().udp_payload_size, 512);
+
+        // Leaving out additional records doesn't make the message truncated
+        let mut additional = packet.clone();
+        additional.resources = additional.answers.split_off(4);
+        let response = written(&mut additional, 512);
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers, packet.answers[..4]);
+        assert!(response.resources.is_empty());
+        assert!(response.edns.is_some());
+
+        let response = written(&mut packet.clone(), 4096);
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers, packet.answers);
+    }
+
+    #[test]
+    fn responses_are_cut_down_to_the_servers_bailiwick() {
+        let packet = |answers: &str, authorities: &str, resources: &str| {
+            let records = |text: &str| zone::parse_master_file(text, Some("")).unwrap();
+            let mut packet = DnsPacket::new();
+            packet.answers = records(answers);
+            packet.authorities = records(authorities);
+            packet.resources = records(resources);
+            packet
+        };
+        let names = |records: &[DnsRecord]| -> Vec<String> {
+            records
+                .iter()
+                .map(|rec| format!("{} {:?}", rec.domain(), rec.qtype()))
+                .collect()
+        };
+
+        // An answer from the servers of example.com
+        let mut response = packet(
+            "www.example.com. 300 IN CNAME alias.example.com.\n\
+            alias.example.com. 300 IN CNAME www.example.net.\n\
+            www.example.net. 300 IN A 192.0.2.66\n\
+            other.example.com. 300 IN A 192.0.2.2\n\
+            alias.example.com. 300 IN A 192.0.2.1\n",
+            "example.com. 300 IN NS ns1.example.com.\n\
+            sub.example.com. 300 IN NS ns1.example.com.\n\
+            com. 300 IN NS ns.example.com.\n\
+            net. 300 IN NS ns.example.com.\n\
+            zz.example.com. 300 IN NSEC example.com. A NSEC\n",
+            "ns1.example.com. 300 IN A 192.0.2.53\n\
+            ns.example.net. 300 IN A 192.0.2.66\n",
+        );
+        response.sanitize("www.example.com", "example.com");
+        assert_eq!(
+            names(&response.answers),
+            [
+                "www.example.com CNAME",
+                "alias.example.com CNAME",
+                "alias.example.com A"
+            ]
+        );
+        assert_eq!(
+            names(&response.authorities),
+            ["example.com NS", "zz.example.com NSEC"]
+        );
+        assert_eq!(names(&response.resources), ["ns1.example.com A"]);
+
+        // A referral from the servers of com, with glue for a name it can't vouch for
+        let mut response = packet(
+            "",
+            "example.com. 300 IN NS ns1.example.com.\n\
+            example.com. 300 IN NS ns.example.net.\n\
+            example.org. 300 IN NS ns1.example.org.\n",
+            "ns1.example.com. 300 IN A 192.0.2.53\n\
+            ns.example.net. 300 IN A 192.0.2.66\n\
+            ns1.example.org. 300 IN A 192.0.2.67\n",
+        );
+        response.sanitize("www.example.com", "com");
+        assert_eq!(
+            names(&response.authorities),
+            ["example.com NS", "example.com NS"]
+        );
+        assert_eq!(names(&response.resources), ["ns1.example.com A"]);
+    }
+
+    #[test]
+    fn failed_resolutions_say_why() {
+        let mut resolution = ResolutionContext::new();
+        let error = loop {
+            if let Err(e) = resolution.follow_cname() {
+                break e;
+            }
+        };
+        let option = extended_error(&error).unwrap();
+        assert_eq!(option.code, EDNS_OPTION_EDE);
+        assert_eq!(option.data[..2], [0, 0]);
+        assert_eq!(
+            String::from_utf8(option.data[2..].to_vec()).unwrap(),
+            "CNAME chain longer than 16"
+        );
+
+        let error = anyhow!(Bogus("No signatures".to_string()));
+        let option = extended_error(&error).unwrap();
+        assert_eq!(option.data, b"\x00\x06No signatures");
+
+        assert!(extended_error(&anyhow!("Timed out")).is_none());
+    }
+
+    #[test]
+    fn unknown_round_trip() {
+        round_trip(DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65280,
+            data: vec![0xDE, 0xAD, 0xBE, 0xEF, 0x00],
+            class: QueryClass::CH,
+            ttl: 120,
+        });
+    }
+
+    #[test]
+    fn unknown_passes_through_a_packet_unchanged() {
+        let capture = [
+            0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, // Header
+            3, b'f', b'o', b'o', 0, 0xFF, 0x00, 0, 1, // Question for TYPE65280
+            0xC0, 0x0C, 0xFF, 0x00, 0, 1, 0, 0, 0, 60, 0, 3, 1, 2, 3, // Answer
+        ];
+
+        let packet = rewrite_capture(&capture);
+        assert_eq!(
+            packet.answers[0].to_string(),
+            "foo. 60 IN TYPE65280 \\# 3 010203"
+        );
+    }
+
+    #[test]
+    fn generic_rdata_in_master_files() {
+        let text = "$ORIGIN example.com.\n\
+                    a 60 IN TYPE65280 \\# 3 0102 03\n\
+                    b 60 IN A \\# 4 0A000001\n\
+                    c 60 IN TYPE99 \\# 0\n";
+        let records = zone::parse_master_file(text, None).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::UNKNOWN {
+                    domain: "a.example.com".to_string(),
+                    qtype: 65280,
+                    data: vec![1, 2, 3],
+                    class: QueryClass::IN,
+                    ttl: 60,
+                },
+                DnsRecord::A {
+                    domain: "b.example.com".to_string(),
+                    addr: Ipv4Addr::new(10, 0, 0, 1),
+                    class: QueryClass::IN,
+                    ttl: 60,
+                },
+                DnsRecord::UNKNOWN {
+                    domain: "c.example.com".to_string(),
+                    qtype: 99,
+                    data: Vec::new(),
+                    class: QueryClass::IN,
+                    ttl: 60,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn records_display_in_presentation_format() {
+        let txt = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            strings: vec![b"say \"hi\"".to_vec(), vec![7]],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+        assert_eq!(
+            txt.to_string(),
+            "example.com. 300 IN TXT \"say \\\"hi\\\"\" \"\\007\""
+        );
+
+        let srv = DnsRecord::SRV {
+            domain: "_ldap._tcp.example.com".to_string(),
+            priority: 1,
+            weight: 2,
+            port: 389,
+            host: "dc.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 600,
+        };
+        assert_eq!(
+            srv.to_string(),
+            "_ldap._tcp.example.com. 600 IN SRV 1 2 389 dc.example.com."
+        );
+
+        let caa = DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 0,
+            tag: "issue".to_string(),
+            value: b"letsencrypt.org".to_vec(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        };
+        assert_eq!(
+            caa.to_string(),
+            "example.com. 3600 IN CAA 0 issue \"letsencrypt.org\""
+        );
+    }
+
+    #[test]
+    fn classes_survive_a_round_trip() {
+        round_trip(DnsRecord::TXT {
+            domain: "version.bind".to_string(),
+            strings: vec![b"dns-server".to_vec()],
+            class: QueryClass::CH,
+            ttl: 0,
+        });
+
+        // An RRset deletion from a dynamic update, class ANY without any RDATA
+        round_trip(DnsRecord::UNKNOWN {
+            domain: "www.example.com".to_string(),
+            qtype: QueryType::A.to_num(),
+            data: Vec::new(),
+            class: QueryClass::ANY,
+            ttl: 0,
+        });
+
+        // Records of other classes need data that fits their type
+        let mut writer = BytePacketWriter::new();
+        DnsRecord::UNKNOWN {
+            domain: "www.example.com".to_string(),
+            qtype: QueryType::A.to_num(),
+            data: Vec::new(),
+            class: QueryClass::IN,
+            ttl: 0,
+        }
+        .write(&mut writer)
+        .unwrap();
+        assert!(DnsRecord::read(&mut BytePacketReader::new(writer.as_bytes())).is_err());
+
+        let question = DnsQuestion::new("id.server".to_string(), QueryType::TXT, QueryClass::CH);
+        let mut writer = BytePacketWriter::new();
+        question.write(&mut writer).unwrap();
+        assert!(writer.as_bytes().ends_with(&[0, 16, 0, 3]));
+
+        let mut read = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0), QueryClass::IN);
+        read.read(&mut BytePacketReader::new(writer.as_bytes()))
+            .unwrap();
+        assert_eq!(read, question);
+    }
+
+    #[test]
+    fn classes_in_master_files() {
+        let text = "$ORIGIN example.com.\n\
+                    a 60 CH TXT \"chaos\"\n\
+                    \tTXT \"still chaos\"\n\
+                    b IN 60 A 10.0.0.1\n\
+                    c 60 CLASS42 TYPE99 \\# 0\n";
+        let records = zone::parse_master_file(text, None).unwrap();
+
+        let classes: Vec<QueryClass> = records.iter().map(DnsRecord::class).collect();
+        assert_eq!(
+            classes,
+            vec![
+                QueryClass::CH,
+                QueryClass::CH,
+                QueryClass::IN,
+                QueryClass::UNKNOWN(42)
+            ]
+        );
+        assert_eq!(
+            records[1].to_string(),
+            "a.example.com. 60 CH TXT \"still chaos\""
+        );
+        assert_eq!(
+            records[3].to_string(),
+            "c.example.com. 60 CLASS42 TYPE99 \\# 0"
+        );
+    }
+
+    #[test]
+    fn opt_round_trip() {
+        let mut edns = Edns::new(1232);
+        edns.dnssec_ok = true;
+        edns.options.push(EdnsOption {
+            code: 10,
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        });
+
+        let mut writer = BytePacketWriter::new();
+        edns.write(&mut writer).unwrap();
+        assert_eq!(writer.pos(), edns.wire_len());
+
+        let mut reader = BytePacketReader::new(writer.as_bytes());
+        reader.step(3).unwrap(); // Owner name and type
+        assert_eq!(Edns::read(&mut reader).unwrap(), edns);
+    }
+
+    #[test]
+    fn a_response_capture() {
+        let capture = [
+            &[
+                0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            ][..],
+            b"\x07example\x03com\x00\x00\x01\x00\x01",
+            b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\x5d\xb8\xd8\x22",
+        ]
+        .concat();
+
+        let packet = rewrite_capture(&capture);
+        assert_eq!(packet.header.id, 0x1234);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: Ipv4Addr::new(93, 184, 216, 34),
+                class: QueryClass::IN,
+                ttl: 3600,
+            }]
+        );
+    }
+
+    #[test]
+    fn aaaa_response_capture() {
+        let capture = [
+            &[
+                0x12, 0x35, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            ][..],
+            b"\x07example\x03com\x00\x00\x1c\x00\x01",
+            b"\xc0\x0c\x00\x1c\x00\x01\x00\x01\x51\x80\x00\x10",
+            b"\x26\x06\x28\x00\x02\x20\x00\x01\x02\x48\x18\x93\x25\xc8\x19\x46",
+        ]
+        .concat();
+
+        let packet = rewrite_capture(&capture);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::AAAA {
+                domain: "example.com".to_string(),
+                addr: "2606:2800:220:1:248:1893:25c8:1946".parse().unwrap(),
+                class: QueryClass::IN,
+                ttl: 86400,
+            }]
+        );
+    }
+
+    #[test]
+    fn nxdomain_response_capture() {
+        let capture = [
+            &[
+                0xAB, 0xCD, 0x81, 0x83, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+            ][..],
+            b"\x04nope\x07example\x03com\x00\x00\x01\x00\x01",
+            b"\xc0\x11\x00\x06\x00\x01\x00\x00\x0e\x10\x00\x26",
+            b"\x02ns\xc0\x11\x0ahostmaster\xc0\x11",
+            b"\x78\xa3\xf1\x75\x00\x00\x1c\x20\x00\x00\x0e\x10\x00\x12\x75\x00\x00\x00\x01\x2c",
+        ]
+        .concat();
+
+        let packet = rewrite_capture(&capture);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(
+            packet.authorities,
+            vec![DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                mname: "ns.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                class: QueryClass::IN,
+                ttl: 3600,
+            }]
+        );
+    }
+
+    #[test]
+    fn mx_response_capture() {
+        let capture = [
+            &[
+                0x00, 0x01, 0x85, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02,
+            ][..],
+            b"\x07example\x03com\x00\x00\x0f\x00\x01",
+            b"\xc0\x0c\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x09\x00\x0a\x04mail\xc0\x0c",
+            b"\xc0\x0c\x00\x02\x00\x01\x00\x00\x0e\x10\x00\x06\x03ns1\xc0\x0c",
+            b"\xc0\x2b\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\xc0\x00\x02\x19",
+            b"\x00\x00\x29\x10\x00\x00\x00\x00\x00\x00\x00",
+        ]
+        .concat();
+
+        let packet = rewrite_capture(&capture);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.edns, Some(Edns::new(4096)));
+        assert_eq!(
+            packet.resources,
+            vec![DnsRecord::A {
+                domain: "mail.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 25),
+                class: QueryClass::IN,
+                ttl: 3600,
+            }]
+        );
+    }
+
+    #[test]
+    fn priming_asks_the_hints_even_with_the_root_ns_cached() {
+        let hint = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = hint.local_addr().unwrap();
+        let ns = |host: &str| DnsRecord::NS {
+            domain: String::new(),
+            host: host.to_string(),
+            class: QueryClass::IN,
+            ttl: 518400,
+        };
+
+        // A client asking for the root NS records leaves them in the cache, without addresses
+        let ctx = context();
+        ctx.cache
+            .write()
+            .unwrap()
+            .insert_records(&[ns("a.root-servers.net")], &[]);
+
+        let responder = std::thread::spawn(move || {
+            let mut buf = vec![0; BUF_LEN];
+            let (len, client) = hint.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut BytePacketReader::new(&buf[..len])).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions = request.questions;
+            response.answers.push(ns("a.root-servers.net"));
+            response.resources.push(DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: Ipv4Addr::new(198, 41, 0, 4),
+                class: QueryClass::IN,
+                ttl: 518400,
+            });
+            let mut writer = BytePacketWriter::new();
+            response.write(&mut writer, BUF_LEN).unwrap();
+            hint.send_to(writer.as_bytes(), client).unwrap();
+        });
+
+        assert_eq!(prime_from(&[server], &ctx).unwrap(), 518400);
+        assert_eq!(
+            *ctx.root_servers.read().unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4))]
+        );
+        responder.join().unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// Longest CNAME chain followed for one question.
+const MAX_CNAME_CHAIN: usize = 16;
+
+/// Most referrals followed for one question, counting those met while looking up the addresses
+/// of nameservers.
+const MAX_REFERRALS: usize = 32;
+
+/// Most queries sent upstream for one question, retries and nameserver lookups included.
+const MAX_QUERIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolutionLimit {
+    CnameChain,
+    Referrals,
+    Queries,
+}
+
+impl fmt::Display for ResolutionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolutionLimit::CnameChain => write!(f, "CNAME chain longer than {}", MAX_CNAME_CHAIN),
+            ResolutionLimit::Referrals => write!(f, "more than {} referrals", MAX_REFERRALS),
+            ResolutionLimit::Queries => write!(f, "more than {} upstream queries", MAX_QUERIES),
+        }
+    }
+}
+
+/// The error a resolution stops with once it runs into one of the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitExceeded(pub ResolutionLimit);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resolution limit exceeded: {}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Bookkeeping for the resolution of a single question, shared by every lookup it leads to. It
+/// makes sure CNAME loops and nameservers that depend on their own zone end in an error instead
+/// of recursing forever.
+#[derive(Debug, Default)]
+pub struct ResolutionContext {
+    cnames: usize,
+    referrals: usize,
+    queries: usize,
+}
+
+impl ResolutionContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn follow_cname(&mut self) -> Result<()> {
+        Self::count(
+            &mut self.cnames,
+            MAX_CNAME_CHAIN,
+            ResolutionLimit::CnameChain,
+        )
+    }
+
+    pub fn follow_referral(&mut self) -> Result<()> {
+        Self::count(
+            &mut self.referrals,
+            MAX_REFERRALS,
+            ResolutionLimit::Referrals,
+        )
+    }
+
+    pub fn send_query(&mut self) -> Result<()> {
+        Self::count(&mut self.queries, MAX_QUERIES, ResolutionLimit::Queries)
+    }
+
+    fn count(counter: &mut usize, max: usize, limit: ResolutionLimit) -> Result<()> {
+        if *counter >= max {
+            return Err(anyhow!(LimitExceeded(limit)));
+        }
+        *counter += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts steps of one kind until the limit stops them, returning how many were allowed.
+    fn steps_allowed(
+        resolution: &mut ResolutionContext,
+        step: fn(&mut ResolutionContext) -> Result<()>,
+    ) -> (usize, LimitExceeded) {
+        let mut allowed = 0;
+        loop {
+            match step(resolution) {
+                Ok(()) => allowed += 1,
+                Err(e) => return (allowed, *e.downcast_ref::<LimitExceeded>().unwrap()),
+            }
+        }
+    }
+
+    #[test]
+    fn each_limit_stops_its_own_steps() {
+        let mut resolution = ResolutionContext::new();
+        assert_eq!(
+            steps_allowed(&mut resolution, ResolutionContext::follow_cname),
+            (MAX_CNAME_CHAIN, LimitExceeded(ResolutionLimit::CnameChain))
+        );
+        assert_eq!(
+            steps_allowed(&mut resolution, ResolutionContext::follow_referral),
+            (MAX_REFERRALS, LimitExceeded(ResolutionLimit::Referrals))
+        );
+        assert_eq!(
+            steps_allowed(&mut resolution, ResolutionContext::send_query),
+            (MAX_QUERIES, LimitExceeded(ResolutionLimit::Queries))
+        );
+
+        // Once reached, a limit stays reached for the rest of the question
+        assert!(resolution.follow_cname().is_err());
+
+        // Every question starts afresh
+        assert!(ResolutionContext::new().follow_cname().is_ok());
+    }
+
+    #[test]
+    fn limits_say_which_one_was_hit() {
+        assert_eq!(
+            LimitExceeded(ResolutionLimit::Referrals).to_string(),
+            "Resolution limit exceeded: more than 32 referrals"
+        );
+        assert_eq!(
+            ResolutionLimit::Queries.to_string(),
+            "more than 64 upstream queries"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Cap on the smoothed RTT, so that a run of timeouts doesn't push a server out of reach for good.
+const MAX_SRTT: Duration = Duration::from_secs(10);
+
+/// Smoothed round-trip times to the servers we have queried, used to ask the fastest of a set of
+/// nameservers first.
+#[derive(Debug, Default)]
+pub struct RttTable {
+    srtt: HashMap<IpAddr, Duration>,
+}
+
+impl RttTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Folds a new measurement into the smoothed RTT of `server`, weighing the history at 7/8
+    /// the way TCP does.
+    pub fn record(&mut self, server: IpAddr, rtt: Duration) {
+        let srtt = match self.srtt.get(&server) {
+            Some(srtt) => (*srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.srtt.insert(server, srtt.min(MAX_SRTT));
+    }
+
+    /// Counts a query that got no usable reply against `server` by doubling its smoothed RTT.
+    pub fn record_failure(&mut self, server: IpAddr, timeout: Duration) {
+        let srtt = self
+            .srtt
+            .get(&server)
+            .map_or(timeout, |srtt| (*srtt * 2).max(timeout));
+        self.srtt.insert(server, srtt.min(MAX_SRTT));
+    }
+
+    /// Orders `servers` fastest first. Servers we haven't measured yet come before all others,
+    /// so that each of them gets a chance.
+    pub fn sort(&self, servers: &mut [SocketAddr]) {
+        servers.sort_by_key(|server| self.srtt.get(&server.ip()).copied().unwrap_or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn sorted(table: &RttTable, servers: &[&str]) -> Vec<String> {
+        let mut servers: Vec<SocketAddr> = servers
+            .iter()
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 53))
+            .collect();
+        table.sort(&mut servers);
+        servers
+            .iter()
+            .map(|server| server.ip().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn faster_servers_are_asked_first() {
+        let mut table = RttTable::new();
+        let (fast, slow) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        table.record(fast, ms(20));
+        table.record(slow, ms(200));
+
+        // Unmeasured servers go first, so they get measured too
+        assert_eq!(
+            sorted(&table, &["192.0.2.2", "192.0.2.1", "192.0.2.3"]),
+            ["192.0.2.3", "192.0.2.1", "192.0.2.2"]
+        );
+
+        // One quick reply doesn't make up for a history of slow ones
+        table.record(slow, ms(8));
+        assert_eq!(table.srtt[&slow], ms(176));
+        assert_eq!(
+            sorted(&table, &["192.0.2.2", "192.0.2.1"]),
+            ["192.0.2.1", "192.0.2.2"]
+        );
+    }
+
+    #[test]
+    fn timeouts_push_servers_back() {
+        let mut table = RttTable::new();
+        let (flaky, steady) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        table.record(flaky, ms(20));
+        table.record(steady, ms(300));
+
+        // A timeout counts at least as much as the timeout itself, and doubles from there
+        table.record_failure(flaky, ms(2000));
+        assert_eq!(table.srtt[&flaky], ms(2000));
+        assert_eq!(
+            sorted(&table, &["192.0.2.1", "192.0.2.2"]),
+            ["192.0.2.2", "192.0.2.1"]
+        );
+        table.record_failure(flaky, ms(2000));
+        assert_eq!(table.srtt[&flaky], ms(4000));
+
+        // But never past the cap, so the server can still win its way back
+        for _ in 0..5 {
+            table.record_failure(flaky, ms(2000));
+        }
+        assert_eq!(table.srtt[&flaky], MAX_SRTT);
+
+        // A server we never heard from starts at the timeout
+        let silent = "192.0.2.3".parse().unwrap();
+        table.record_failure(silent, ms(2000));
+        assert_eq!(table.srtt[&silent], ms(2000));
+    }
+}
@@ -0,0 +1,537 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::dnssec;
+use crate::zone::{serial_newer, Zone, ZoneStore};
+use crate::{
+    exchange, read_tcp_message, tcp_connect, write_tcp_message, BytePacketReader, BytePacketWriter,
+    DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType, ResultCode, ServerContext, BUF_LEN,
+};
+
+/// How long to wait before asking the primaries again while we have no copy of the zone, and so
+/// no SOA to take the retry interval from.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// Where one of the zones we are a secondary for stands.
+struct Secondary {
+    primaries: Vec<SocketAddr>,
+    /// When to ask the primaries for a newer version next.
+    refresh_at: Instant,
+    /// When our copy stops being served, unless a primary could be reached before then.
+    expire_at: Option<Instant>,
+}
+
+impl Secondary {
+    /// When the refresh loop next has something to do for this zone.
+    fn next_event(&self) -> Instant {
+        self.expire_at
+            .map_or(self.refresh_at, |expire_at| expire_at.min(self.refresh_at))
+    }
+}
+
+/// The zones we copy from their primaries with zone transfers, and keep up to date on the
+/// timers of their SOA records (RFC 1034 section 4.3.5).
+pub struct Secondaries {
+    zones: Mutex<HashMap<String, Secondary>>,
+    /// Wakes up the refresh loop when a NOTIFY makes a zone due early.
+    wakeup: Condvar,
+}
+
+/// The refresh, retry and expire intervals of an SOA record.
+fn soa_timers(soa: &DnsRecord) -> (Duration, Duration, Duration) {
+    match soa {
+        DnsRecord::SOA {
+            refresh,
+            retry,
+            expire,
+            ..
+        } => (
+            Duration::from_secs((*refresh).into()),
+            Duration::from_secs((*retry).into()),
+            Duration::from_secs((*expire).into()),
+        ),
+        _ => (INITIAL_RETRY, INITIAL_RETRY, Duration::MAX),
+    }
+}
+
+fn soa_serial(soa: &DnsRecord) -> Option<u32> {
+    match soa {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// Whether the records received so far make up a whole transfer. It ends with the SOA of the
+/// new version, which AXFR carries twice and IXFR three times, the middle one closing the last
+/// change. A lone SOA in the first message of an IXFR means the client is up to date, where an
+/// AXFR may just be sending one record per message (RFC 5936 section 2.2).
+fn is_complete(records: &[DnsRecord], messages: usize, axfr: bool) -> Result<bool> {
+    let serial = records
+        .first()
+        .and_then(soa_serial)
+        .ok_or_else(|| anyhow!("The transfer doesn't start with an SOA"))?;
+    let soas = records
+        .iter()
+        .filter(|rec| soa_serial(rec) == Some(serial))
+        .count();
+
+    Ok(match records.get(1).map(soa_serial) {
+        None => !axfr && messages == 1,
+        Some(_) if axfr => soas == 2,
+        Some(Some(old)) if old != serial => soas == 3,
+        Some(_) => soas == 2,
+    })
+}
+
+/// Works out the records of the new version of a zone from a transfer, or `None` when it only
+/// says that `current` is up to date. IXFR responses are sequences of changes, each being the
+/// SOA of the old version and the records deleted from it, then the SOA of the new version and
+/// the records added to it (RFC 1995 section 4). Anything else holds the whole zone.
+fn apply_transfer(current: Option<&Zone>, records: &[DnsRecord]) -> Result<Option<Vec<DnsRecord>>> {
+    let new_soa = &records[0];
+    let Some(second) = records.get(1) else {
+        return match current {
+            Some(_) => Ok(None),
+            None => Err(anyhow!(
+                "The transfer only holds an SOA, with no zone to keep"
+            )),
+        };
+    };
+    let body = &records[1..records.len() - 1];
+
+    let incremental = match (current, soa_serial(second)) {
+        (Some(zone), Some(from)) if Some(from) != soa_serial(new_soa) => {
+            if from != zone.serial() {
+                return Err(anyhow!(
+                    "The changes start from serial {}, but we have {}",
+                    from,
+                    zone.serial()
+                ));
+            }
+            zone
+        }
+        _ => {
+            let mut zone = body.to_vec();
+            zone.push(new_soa.clone());
+            return Ok(Some(zone));
+        }
+    };
+
+    let transfer = incremental.transfer();
+    let mut zone: HashSet<DnsRecord> = transfer[1..transfer.len() - 1].iter().cloned().collect();
+    let mut adding = true;
+    for rec in body {
+        if rec.qtype() == QueryType::SOA {
+            adding = !adding;
+        } else if adding {
+            zone.insert(rec.clone());
+        } else {
+            zone.remove(rec);
+        }
+    }
+
+    let mut zone: Vec<DnsRecord> = zone.into_iter().collect();
+    zone.push(new_soa.clone());
+    Ok(Some(zone))
+}
+
+/// Stops serving our copies of the zones that weren't refreshed before they expired.
+fn expire(zones: &mut HashMap<String, Secondary>, store: &RwLock<ZoneStore>, now: Instant) {
+    for (origin, secondary) in zones.iter_mut() {
+        if secondary
+            .expire_at
+            .is_some_and(|expire_at| expire_at <= now)
+        {
+            eprintln!("Zone {} expired, no longer serving it", origin);
+            secondary.expire_at = None;
+            store.write().unwrap().remove(origin);
+        }
+    }
+}
+
+/// Transfers `origin` from `primary` over TCP, incrementally if we already have a version of it
+/// to start from. Returns the records of the transfer as they came.
+fn transfer(
+    origin: &str,
+    current: Option<&DnsRecord>,
+    primary: SocketAddr,
+    ctx: &ServerContext,
+) -> Result<Vec<DnsRecord>> {
+    let mut request = DnsPacket::new();
+    request.header.id = rand::random();
+    let qtype = match current {
+        Some(soa) => {
+            request.authorities.push(soa.clone());
+            QueryType::IXFR
+        }
+        None => QueryType::AXFR,
+    };
+    request
+        .questions
+        .push(DnsQuestion::new(origin.to_string(), qtype, QueryClass::IN));
+
+    let mut req_buf = BytePacketWriter::new();
+    request.write(&mut req_buf, BUF_LEN)?;
+
+    let source = ctx.config.source_for(&primary)?;
+    let mut stream = tcp_connect(source, primary, ctx.config.query_timeout())?;
+    write_tcp_message(&mut stream, &req_buf)?;
+
+    let mut records = Vec::new();
+    let mut messages = 0;
+    loop {
+        let res_buf = read_tcp_message(&mut stream)?;
+        let response = DnsPacket::from_buffer(&mut BytePacketReader::new(&res_buf))?;
+        messages += 1;
+
+        // Only the first message has to repeat the question
+        if !response.header.response
+            || response.header.id != request.header.id
+            || (messages == 1 && response.questions != request.questions)
+        {
+            return Err(anyhow!("Reply from {} doesn't match the request", primary));
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(anyhow!(
+                "{} answered {:?} for {} of {}",
+                primary,
+                response.header.rescode,
+                qtype,
+                origin
+            ));
+        }
+
+        records.extend(response.answers);
+        if is_complete(&records, messages, qtype == QueryType::AXFR)? {
+            return Ok(records);
+        }
+    }
+}
+
+/// Brings our copy of `origin` up to date with `primary`, if it has a newer version.
+fn refresh_from(origin: &str, primary: SocketAddr, ctx: &ServerContext) -> Result<()> {
+    let current = ctx
+        .zones
+        .read()
+        .unwrap()
+        .find(origin)
+        .filter(|zone| zone.origin == origin)
+        .map(|zone| zone.soa().clone());
+
+    // Asking for the SOA is cheap, and tells us whether there is anything to transfer
+    if let Some(serial) = current.as_ref().and_then(soa_serial) {
+        let mut request = DnsPacket::new();
+        request.header.id = rand::random();
+        request.questions.push(DnsQuestion::new(
+            origin.to_string(),
+            QueryType::SOA,
+            QueryClass::IN,
+        ));
+        let mut req_buf = BytePacketWriter::new();
+        request.write(&mut req_buf, BUF_LEN)?;
+
+        let source = ctx.config.source_for(&primary)?;
+        let response = exchange(
+            &req_buf,
+            &request,
+            source,
+            primary,
+            ctx.config.query_timeout(),
+        )?;
+        let primary_serial = response
+            .answers
+            .iter()
+            .filter(|rec| rec.domain() == origin)
+            .find_map(soa_serial)
+            .ok_or_else(|| anyhow!("{} has no SOA for {}", primary, origin))?;
+
+        if !serial_newer(primary_serial, serial) {
+            return Ok(());
+        }
+    }
+
+    let records = transfer(origin, current.as_ref(), primary, ctx)?;
+
+    let mut zones = ctx.zones.write().unwrap();
+    let existing = zones.find(origin).filter(|zone| zone.origin == origin);
+    let records = match apply_transfer(existing, &records) {
+        // The primary may have lost the history we need, which a full transfer doesn't depend on
+        Err(e) if current.is_some() => {
+            drop(zones);
+            eprintln!("{}, asking {} for all of {}", e, primary, origin);
+            let records = transfer(origin, None, primary, ctx)?;
+            zones = ctx.zones.write().unwrap();
+            apply_transfer(None, &records)?
+        }
+        result => result?,
+    };
+    let Some(records) = records else {
+        return Ok(());
+    };
+
+    let zone = Zone::new(records)?;
+    if zone.origin != origin {
+        return Err(anyhow!("{} sent zone {} instead", primary, zone.origin));
+    }
+    zones.update(zone, dnssec::now())?;
+    println!(
+        "Transferred zone {} from {}, now at serial {}",
+        origin,
+        primary,
+        zones.find(origin).map_or(0, Zone::serial)
+    );
+
+    Ok(())
+}
+
+impl Secondaries {
+    /// Sets up the secondary zones in `zones`, with the primaries to transfer them from. They
+    /// are all due for a transfer right away.
+    pub fn new(zones: &HashMap<String, Vec<SocketAddr>>, store: &ZoneStore) -> Result<Self> {
+        let now = Instant::now();
+        let mut secondaries = HashMap::new();
+
+        for (origin, primaries) in zones {
+            if store
+                .find(origin)
+                .is_some_and(|zone| zone.origin == *origin)
+            {
+                return Err(anyhow!(
+                    "{} is loaded from a zone file, it can't also be a secondary zone",
+                    origin
+                ));
+            }
+
+            secondaries.insert(
+                origin.clone(),
+                Secondary {
+                    primaries: primaries.clone(),
+                    refresh_at: now,
+                    expire_at: None,
+                },
+            );
+        }
+
+        Ok(Self {
+            zones: Mutex::new(secondaries),
+            wakeup: Condvar::new(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.lock().unwrap().is_empty()
+    }
+
+    /// Handles a NOTIFY for `zone` from `client` (RFC 1996). Only the primaries of the zone are
+    /// listened to, and they make it due for a refresh right away.
+    pub fn notify(&self, zone: &str, client: IpAddr) -> ResultCode {
+        let mut zones = self.zones.lock().unwrap();
+
+        match zones.get_mut(zone) {
+            None => ResultCode::NOTAUTH,
+            Some(secondary) if !secondary.primaries.iter().any(|p| p.ip() == client) => {
+                eprintln!("Ignoring NOTIFY for {} from {}", zone, client);
+                ResultCode::REFUSED
+            }
+            Some(secondary) => {
+                println!("Received NOTIFY for {} from {}", zone, client);
+                secondary.refresh_at = Instant::now();
+                self.wakeup.notify_all();
+                ResultCode::NOERROR
+            }
+        }
+    }
+
+    /// Refreshes each zone when it is due, going through its primaries until one of them has
+    /// answered. Our copy is kept for as long as the SOA says it may be, and after that it is
+    /// no longer served.
+    pub fn run(&self, ctx: &ServerContext) {
+        loop {
+            let due: Vec<(String, Vec<SocketAddr>)> = {
+                let mut zones = self.zones.lock().unwrap();
+                loop {
+                    let now = Instant::now();
+                    expire(&mut zones, &ctx.zones, now);
+                    let due: Vec<_> = zones
+                        .iter()
+                        .filter(|(_, secondary)| secondary.refresh_at <= now)
+                        .map(|(origin, secondary)| (origin.clone(), secondary.primaries.clone()))
+                        .collect();
+                    if !due.is_empty() {
+                        break due;
+                    }
+
+                    let Some(next) = zones.values().map(Secondary::next_event).min() else {
+                        return;
+                    };
+                    zones = self
+                        .wakeup
+                        .wait_timeout(zones, next.saturating_duration_since(now))
+                        .unwrap()
+                        .0;
+                }
+            };
+
+            for (origin, primaries) in due {
+                let started = Instant::now();
+                let mut result = Err(anyhow!("No primaries for {}", origin));
+                for primary in &primaries {
+                    result = refresh_from(&origin, *primary, ctx).map_err(|e| {
+                        anyhow!("Refreshing {} from {} failed: {}", origin, primary, e)
+                    });
+                    match &result {
+                        Ok(()) => break,
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+
+                let soa = ctx
+                    .zones
+                    .read()
+                    .unwrap()
+                    .find(&origin)
+                    .filter(|zone| zone.origin == origin)
+                    .map(|zone| zone.soa().clone());
+                let (refresh, retry, expire) = soa
+                    .as_ref()
+                    .map_or((INITIAL_RETRY, INITIAL_RETRY, Duration::MAX), soa_timers);
+
+                let mut zones = self.zones.lock().unwrap();
+                let Some(secondary) = zones.get_mut(&origin) else {
+                    continue;
+                };
+                // A NOTIFY that came in while we were at it makes the zone due again
+                let notified = secondary.refresh_at > started;
+                let now = Instant::now();
+
+                match result {
+                    Ok(()) => {
+                        secondary.expire_at = now.checked_add(expire);
+                        if !notified {
+                            secondary.refresh_at = now + refresh;
+                        }
+                    }
+                    Err(_) => {
+                        if !notified {
+                            secondary.refresh_at = now + retry;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::zone::parse_master_file;
+
+    /// The zone as it stands at `serial`, with an address for each of `hosts`.
+    fn version(serial: u32, hosts: &[&str]) -> Zone {
+        let mut text = format!(
+            "$ORIGIN example.com.\n$TTL 3600\n\
+            @ IN SOA ns1 admin {} 7200 3600 1209600 300\n\
+            @ IN NS ns1\n\
+            ns1 IN A 192.0.2.53\n",
+            serial
+        );
+        for host in hosts {
+            text.push_str(&format!("{} IN A 192.0.2.1\n", host));
+        }
+        Zone::new(parse_master_file(&text, None).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn secondaries_follow_incremental_transfers() {
+        let sorted = |mut records: Vec<DnsRecord>| {
+            records.sort();
+            records
+        };
+
+        let mut primary = version(1, &["www"]);
+        primary.replace(version(2, &["www", "mail"]), 0).unwrap();
+        primary.replace(version(3, &["mail", "ftp"]), 0).unwrap();
+        let secondary = version(1, &["www"]);
+
+        let ixfr = primary.transfer_since(1).unwrap();
+        assert!(is_complete(&ixfr, 3, false).unwrap());
+        assert!(!is_complete(&ixfr[..ixfr.len() - 1], 1, false).unwrap());
+        let records = apply_transfer(Some(&secondary), &ixfr).unwrap().unwrap();
+        let transfer = primary.transfer();
+        assert_eq!(sorted(records), sorted(transfer[1..].to_vec()));
+
+        // Changes that don't start from the version we have can't be applied
+        let ixfr = primary.transfer_since(2).unwrap();
+        assert!(apply_transfer(Some(&secondary), &ixfr).is_err());
+
+        // A full transfer replaces whatever we had, a lone SOA leaves it as it is
+        let axfr = primary.transfer();
+        assert!(is_complete(&axfr, 1, true).unwrap());
+        assert!(!is_complete(&axfr[..2], 1, true).unwrap());
+        let records = apply_transfer(Some(&secondary), &axfr).unwrap().unwrap();
+        assert_eq!(sorted(records), sorted(axfr[1..].to_vec()));
+
+        assert!(is_complete(&axfr[..1], 1, false).unwrap());
+        assert!(apply_transfer(Some(&secondary), &axfr[..1])
+            .unwrap()
+            .is_none());
+
+        // An AXFR sent one record per message isn't over after the first SOA
+        assert!(!is_complete(&axfr[..1], 1, true).unwrap());
+        assert!(!is_complete(&axfr[..axfr.len() - 1], axfr.len() - 1, true).unwrap());
+        assert!(is_complete(&axfr, axfr.len(), true).unwrap());
+        assert!(apply_transfer(None, &axfr[..1]).is_err());
+    }
+
+    #[test]
+    fn expired_zones_are_no_longer_served() {
+        let mut store = ZoneStore::new();
+        store.update(version(1, &["www"]), 0).unwrap();
+        let store = RwLock::new(store);
+
+        let now = Instant::now();
+        let mut zones = HashMap::from([(
+            "example.com".to_string(),
+            Secondary {
+                primaries: Vec::new(),
+                refresh_at: now + Duration::from_secs(3600),
+                expire_at: Some(now + Duration::from_secs(60)),
+            },
+        )]);
+        // The loop has to wake up for the expiry even though no refresh is due before it
+        assert_eq!(
+            zones["example.com"].next_event(),
+            now + Duration::from_secs(60)
+        );
+
+        expire(&mut zones, &store, now);
+        assert!(store.read().unwrap().find("example.com").is_some());
+
+        expire(&mut zones, &store, now + Duration::from_secs(60));
+        assert!(store.read().unwrap().find("example.com").is_none());
+        assert_eq!(zones["example.com"].expire_at, None);
+        assert_eq!(
+            zones["example.com"].next_event(),
+            now + Duration::from_secs(3600)
+        );
+    }
+
+    #[test]
+    fn only_primaries_may_notify() {
+        let config = Config::from_args(
+            ["--secondary", "Example.com.=192.0.2.10,192.0.2.11:5353"].map(String::from),
+        )
+        .unwrap();
+        let secondaries = Secondaries::new(&config.secondary_zones, &ZoneStore::new()).unwrap();
+
+        let notify = |zone: &str, client: &str| secondaries.notify(zone, client.parse().unwrap());
+        assert_eq!(notify("example.com", "192.0.2.11"), ResultCode::NOERROR);
+        assert_eq!(notify("example.com", "192.0.2.12"), ResultCode::REFUSED);
+        assert_eq!(notify("example.org", "192.0.2.10"), ResultCode::NOTAUTH);
+    }
+}
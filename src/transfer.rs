@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+
+use crate::zone::serial_newer;
+use crate::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, ServerContext};
+
+/// Answers an AXFR or IXFR request for one of our zones, returning the records of the transfer,
+/// which may take more than one message to send. IXFR over UDP only gets the current SOA, which
+/// tells clients that are behind to come back over TCP (RFC 1995 section 2).
+pub fn answer(
+    question: &DnsQuestion,
+    request: &DnsPacket,
+    client: IpAddr,
+    is_udp: bool,
+    ctx: &ServerContext,
+    packet: &mut DnsPacket,
+) -> Vec<DnsRecord> {
+    if !ctx.config.may_transfer(client) {
+        eprintln!("Refused transfer of {} to {}", question.name, client);
+        packet.header.rescode = ResultCode::REFUSED;
+        return Vec::new();
+    }
+
+    let zones = ctx.zones.read().unwrap();
+    let Some(zone) = zones
+        .find(&question.name)
+        .filter(|zone| zone.origin == question.name && zone.class() == question.qclass)
+    else {
+        packet.header.rescode = ResultCode::NOTAUTH;
+        return Vec::new();
+    };
+    packet.header.authoritative_answer = true;
+
+    let records = match question.qtype {
+        // AXFR is only ever done over TCP (RFC 5936 section 4.2)
+        QueryType::AXFR if is_udp => {
+            packet.header.rescode = ResultCode::FORMERR;
+            return Vec::new();
+        }
+        QueryType::AXFR => zone.transfer(),
+        _ => {
+            // The client tells us which version it has with an SOA in the authority section
+            let Some(serial) = request.authorities.iter().find_map(|rec| match rec {
+                DnsRecord::SOA { serial, .. } => Some(*serial),
+                _ => None,
+            }) else {
+                packet.header.rescode = ResultCode::FORMERR;
+                return Vec::new();
+            };
+
+            if is_udp || !serial_newer(zone.serial(), serial) {
+                return vec![zone.soa().clone()];
+            }
+            // Without the changes in the journal, the client gets the whole zone instead
+            zone.transfer_since(serial)
+                .unwrap_or_else(|| zone.transfer())
+        }
+    };
+
+    println!(
+        "Transferring zone {} at serial {} to {}",
+        zone.origin,
+        zone.serial(),
+        client
+    );
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone::{parse_master_file, Zone};
+    use crate::{write_transfer, BytePacketReader, QueryClass, MAX_PACKET_LEN};
+
+    /// The zone as it stands at `serial`, with www at `addr`.
+    fn version(serial: u32, addr: &str) -> Zone {
+        let text = format!(
+            "$ORIGIN example.com.\n$TTL 3600\n\
+            @ IN SOA ns1 admin {} 7200 3600 1209600 300\n\
+            @ IN NS ns1\n\
+            ns1 IN A 192.0.2.53\n\
+            www IN A {}\n",
+            serial, addr
+        );
+        Zone::new(parse_master_file(&text, None).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn zone_changes_are_journaled_for_ixfr() {
+        let a = |addr: [u8; 4]| DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: addr.into(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        };
+
+        let mut zone = version(1, "192.0.2.1");
+        let soa1 = zone.soa().clone();
+        zone.replace(version(2, "192.0.2.2"), 0).unwrap();
+        let soa2 = zone.soa().clone();
+        zone.replace(version(3, "192.0.2.3"), 0).unwrap();
+        let soa3 = zone.soa().clone();
+
+        let axfr = zone.transfer();
+        assert_eq!(axfr.len(), 5);
+        assert_eq!((&axfr[0], &axfr[4]), (&soa3, &soa3));
+        assert!(axfr.contains(&a([192, 0, 2, 3])));
+
+        assert_eq!(
+            zone.transfer_since(1).unwrap(),
+            [
+                soa3.clone(),
+                soa1,
+                a([192, 0, 2, 1]),
+                soa2.clone(),
+                a([192, 0, 2, 2]),
+                soa2,
+                a([192, 0, 2, 2]),
+                soa3.clone(),
+                a([192, 0, 2, 3]),
+                soa3,
+            ]
+        );
+        assert_eq!(zone.transfer_since(2).unwrap().len(), 6);
+        // Versions the journal doesn't know of need a full transfer
+        assert!(zone.transfer_since(7).is_none());
+
+        assert!(serial_newer(1, u32::MAX));
+        assert!(!serial_newer(3, 3));
+    }
+
+    #[test]
+    fn large_transfers_span_several_messages() {
+        // Many small RRsets, then a single RRset larger than a message
+        for owner in [
+            |i| format!("host{}.example.com", i),
+            |_| "example.com".to_string(),
+        ] {
+            let records: Vec<DnsRecord> = (0..3000u32)
+                .map(|i| DnsRecord::TXT {
+                    domain: owner(i),
+                    strings: vec![i.to_string().into_bytes(), vec![b'x'; 100]],
+                    class: QueryClass::IN,
+                    ttl: 3600,
+                })
+                .collect();
+
+            let mut packet = DnsPacket::new();
+            packet.header.response = true;
+            packet.questions.push(DnsQuestion::new(
+                "example.com".to_string(),
+                QueryType::AXFR,
+                QueryClass::IN,
+            ));
+            let messages = write_transfer(packet, &records).unwrap();
+            assert!(messages.len() > 3);
+
+            let mut received = Vec::new();
+            for (i, message) in messages.iter().enumerate() {
+                assert!(message.pos() <= MAX_PACKET_LEN);
+                let packet =
+                    DnsPacket::from_buffer(&mut BytePacketReader::new(message.as_bytes())).unwrap();
+                assert!(!packet.header.truncated_message);
+                assert_eq!(packet.questions.len(), (i == 0) as usize);
+                received.extend(packet.answers);
+            }
+            assert_eq!(received, records);
+        }
+    }
+}